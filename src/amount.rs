@@ -0,0 +1,90 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+pub const NOLAN_PER_SAITO: u64 = 100_000_000;
+
+// a quantity of SAITO held as an integer number of nolan
+//
+// all arithmetic is checked and every division rounds down, whatever is
+// lost to rounding is left where it came from (the treasury, the node
+// share) so that every node arrives at the same values
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Default)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub fn zero() -> Amount {
+        return Amount(0);
+    }
+
+    pub fn from_nolan(nolan: u64) -> Amount {
+        return Amount(nolan);
+    }
+
+    pub fn from_saito(saito: u64) -> Amount {
+        return Amount(saito.checked_mul(NOLAN_PER_SAITO).expect("amount overflow"));
+    }
+
+    pub fn return_nolan(&self) -> u64 {
+        return self.0;
+    }
+
+    pub fn is_zero(&self) -> bool {
+        return self.0 == 0;
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        return self.0.checked_add(other.0).map(Amount);
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        return self.0.checked_sub(other.0).map(Amount);
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        return Amount(self.0.saturating_sub(other.0));
+    }
+
+    // rounds down
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+        return self.0.checked_div(divisor).map(Amount);
+    }
+
+    // self * numerator / denominator without intermediate overflow, rounds down
+    pub fn checked_mul_ratio(self, numerator: u64, denominator: u64) -> Option<Amount> {
+        if denominator == 0 { return None; }
+        let result = self.0 as u128 * numerator as u128 / denominator as u128;
+        if result > u64::max_value() as u128 { return None; }
+        return Some(Amount(result as u64));
+    }
+
+    pub fn checked_sum<I: Iterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        let mut total = Amount::zero();
+        for amount in amounts {
+            total = total.checked_add(amount)?;
+        }
+        return Some(total);
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}.{:08}", self.0 / NOLAN_PER_SAITO, self.0 % NOLAN_PER_SAITO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amount_arithmetic_is_checked_and_rounds_down() {
+        let treasury = Amount::from_saito(2868100000);
+        assert_eq!(treasury.return_nolan(), 286810000000000000);
+        assert_eq!(treasury.checked_div(21600), Some(Amount::from_nolan(13278240740740)));
+        assert_eq!(Amount::from_nolan(7).checked_mul_ratio(5000, 10000), Some(Amount::from_nolan(3)));
+        assert_eq!(Amount::from_nolan(u64::max_value()).checked_add(Amount::from_nolan(1)), None);
+        assert_eq!(Amount::from_nolan(1).checked_sub(Amount::from_nolan(2)), None);
+        assert_eq!(format!("{}", Amount::from_nolan(150000000)), "1.50000000");
+    }
+}
@@ -0,0 +1,202 @@
+// Choosing which of the wallet's unspent slips pay for a transaction
+//
+// Every strategy sees the candidates in the same order, oldest slip first
+// and ties broken by slip index, so the same wallet always picks the same
+// inputs for the same amount.
+
+use std::cmp::Ordering;
+
+use crate::{Amount, Slip};
+
+// how many branches the exact match search explores before giving up
+const BRANCH_AND_BOUND_MAX_TRIES: u32 = 100_000;
+
+pub trait CoinSelection: Send {
+    // the inputs to spend for amount, taken from candidates in the order
+    // returned by sort_candidates, or None if they do not cover it
+    fn select(&self, candidates: &[Slip], amount: Amount) -> Option<Vec<Slip>>;
+}
+
+// spends the slips closest to expiring at GENESIS_PERIOD first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OldestFirst;
+
+// fewest inputs, the largest slips first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+// consolidates dust, the smallest slips first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmallestFirst;
+
+// looks for a set of inputs that adds up to exactly the amount, so the
+// transaction needs no change, and falls back to largest first otherwise
+#[derive(Debug, Clone, Copy)]
+pub struct BranchAndBound {
+    max_tries: u32,
+}
+
+impl BranchAndBound {
+    pub fn new() -> BranchAndBound {
+        return BranchAndBound { max_tries: BRANCH_AND_BOUND_MAX_TRIES };
+    }
+
+    pub fn with_max_tries(max_tries: u32) -> BranchAndBound {
+        return BranchAndBound { max_tries };
+    }
+
+    fn search(&self, slips: &[Slip], remaining: &[Amount], index: usize, amount: Amount,
+              selected: &mut Vec<usize>, tries: &mut u32) -> bool {
+        if amount.is_zero() { return true; }
+        if index >= slips.len() || remaining[index] < amount { return false; }
+        if *tries >= self.max_tries { return false; }
+        *tries += 1;
+
+        if slips[index].amount <= amount {
+            selected.push(index);
+            if self.search(slips, remaining, index + 1, amount.saturating_sub(slips[index].amount), selected, tries) {
+                return true;
+            }
+            selected.pop();
+        }
+
+        return self.search(slips, remaining, index + 1, amount, selected, tries);
+    }
+}
+
+impl Default for BranchAndBound {
+    fn default() -> BranchAndBound {
+        return BranchAndBound::new();
+    }
+}
+
+impl CoinSelection for OldestFirst {
+    fn select(&self, candidates: &[Slip], amount: Amount) -> Option<Vec<Slip>> {
+        return select_in_order(candidates.to_vec(), amount);
+    }
+}
+
+impl CoinSelection for LargestFirst {
+    fn select(&self, candidates: &[Slip], amount: Amount) -> Option<Vec<Slip>> {
+        let mut slips = candidates.to_vec();
+        slips.sort_by(|a, b| b.amount.cmp(&a.amount));
+        return select_in_order(slips, amount);
+    }
+}
+
+impl CoinSelection for SmallestFirst {
+    fn select(&self, candidates: &[Slip], amount: Amount) -> Option<Vec<Slip>> {
+        let mut slips = candidates.to_vec();
+        slips.sort_by(|a, b| a.amount.cmp(&b.amount));
+        return select_in_order(slips, amount);
+    }
+}
+
+impl CoinSelection for BranchAndBound {
+    fn select(&self, candidates: &[Slip], amount: Amount) -> Option<Vec<Slip>> {
+        let mut slips: Vec<Slip> = candidates.iter().filter(|slip| !slip.amount.is_zero()).cloned().collect();
+        slips.sort_by(|a, b| b.amount.cmp(&a.amount));
+
+        // remaining[i] is what the slips from i onwards add up to, which
+        // prunes every branch that can no longer reach the amount
+        let mut remaining = vec![Amount::zero(); slips.len() + 1];
+        for i in (0..slips.len()).rev() {
+            remaining[i] = remaining[i + 1].checked_add(slips[i].amount)?;
+        }
+
+        let mut selected: Vec<usize> = Vec::new();
+        let mut tries = 0;
+        if !amount.is_zero() && self.search(&slips, &remaining, 0, amount, &mut selected, &mut tries) {
+            return Some(selected.iter().map(|i| slips[*i].clone()).collect());
+        }
+
+        return LargestFirst.select(candidates, amount);
+    }
+}
+
+// what spending amount with fee would look like, without creating anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpendPlan {
+    pub inputs: Vec<Slip>,
+    pub amount: Amount,
+    pub fee: Amount,
+    pub change: Amount,
+}
+
+impl SpendPlan {
+    pub fn new(inputs: Vec<Slip>, amount: Amount, fee: Amount) -> Option<SpendPlan> {
+        let total = Amount::checked_sum(inputs.iter().map(|slip| slip.amount))?;
+        let change = total.checked_sub(amount.checked_add(fee)?)?;
+        return Some(SpendPlan { inputs, amount, fee, change });
+    }
+}
+
+// the order every strategy starts from, oldest slip first
+pub fn sort_candidates(slips: &mut Vec<Slip>) {
+    slips.sort_by(|a, b| compare_age(a, b));
+}
+
+fn compare_age(a: &Slip, b: &Slip) -> Ordering {
+    return a.block_id.cmp(&b.block_id)
+        .then(a.transaction_id.cmp(&b.transaction_id))
+        .then(a.id.cmp(&b.id))
+        .then_with(|| a.return_index().cmp(&b.return_index()));
+}
+
+// takes slips in order until they cover the amount, always at least one
+fn select_in_order(slips: Vec<Slip>, amount: Amount) -> Option<Vec<Slip>> {
+    let mut selected: Vec<Slip> = Vec::new();
+    let mut selected_amount = Amount::zero();
+
+    for slip in slips.into_iter() {
+        selected_amount = selected_amount.checked_add(slip.amount)?;
+        selected.push(slip);
+        if selected_amount >= amount {
+            return Some(selected);
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Wallet;
+
+    fn create_slip(wallet: &Wallet, block_id: u32, amount: u64) -> Slip {
+        let mut slip = Slip::new(wallet.return_publickey());
+        slip.block_id = block_id;
+        slip.amount = Amount::from_nolan(amount);
+        return slip;
+    }
+
+    fn return_amounts(slips: Option<Vec<Slip>>) -> Vec<u64> {
+        return slips.unwrap().iter().map(|slip| slip.amount.return_nolan()).collect();
+    }
+
+    #[test]
+    fn coin_selection_strategies_pick_deterministic_inputs() {
+        let mut wallet = Wallet::new();
+        for (block_id, amount) in vec![(4, 1), (1, 3), (3, 5), (2, 2)] {
+            wallet.add_input(create_slip(&wallet, block_id, amount));
+        }
+
+        let candidates = wallet.return_spendable_inputs();
+        let amount = Amount::from_nolan(6);
+
+        assert_eq!(return_amounts(OldestFirst.select(&candidates, amount)), vec![3, 2, 5]);
+        assert_eq!(return_amounts(LargestFirst.select(&candidates, amount)), vec![5, 3]);
+        assert_eq!(return_amounts(SmallestFirst.select(&candidates, amount)), vec![1, 2, 3]);
+        assert_eq!(return_amounts(BranchAndBound::new().select(&candidates, amount)), vec![5, 1]);
+        assert_eq!(return_amounts(BranchAndBound::new().select(&candidates, Amount::from_nolan(11))), vec![5, 3, 2, 1]);
+        assert_eq!(OldestFirst.select(&candidates, Amount::from_nolan(12)), None);
+
+        // a dry run reports what would be spent and changes nothing
+        wallet.set_coin_selection(Box::new(LargestFirst));
+        let plan = wallet.dry_run_transaction(Amount::from_nolan(1), Amount::from_nolan(5)).unwrap();
+        assert_eq!(return_amounts(Some(plan.inputs)), vec![5, 3]);
+        assert_eq!(plan.change, Amount::from_nolan(2));
+        assert_eq!(plan.fee, Amount::from_nolan(1));
+        assert_eq!(wallet.return_available_balance(), Amount::from_nolan(11));
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::{File, create_dir_all, rename};
+use std::io;
+use std::io::Read;
+use std::io::prelude::*;
+use std::path::Path;
+
+use base58::{FromBase58, ToBase58};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ring::aead::{self, CHACHA20_POLY1305, OpeningKey, SealingKey};
+use ring::digest::SHA256;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use secp256k1::{PublicKey, SecretKey};
+
+use crate::Wallet;
+
+static WALLET_FILE_MAGIC: &[u8; 4] = b"SWAL";
+const WALLET_FILE_VERSION: u16 = 1;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const PUBLICKEY_LEN: usize = 33;
+
+// the wallet file only holds the keypair, every slip the wallet owns is
+// found again when the blockchain is replayed into it on startup
+//
+// magic (4) + version (2) + pbkdf2 iterations (4) + salt (16) + nonce (12)
+// + publickey (33) + secret key sealed with chacha20-poly1305 (32 + 16)
+//
+// the key for the seal is derived from the passphrase with pbkdf2-sha256 and
+// the publickey is authenticated along with it, so a wrong passphrase and a
+// tampered file are both caught before the secret key is used
+#[derive(Debug)]
+pub enum WalletFileError {
+    Io(io::Error),
+    Corrupt,
+    WrongPassphrase,
+    InvalidKey,
+}
+
+impl From<io::Error> for WalletFileError {
+    fn from(error: io::Error) -> WalletFileError {
+        return WalletFileError::Io(error);
+    }
+}
+
+impl Wallet {
+    pub fn from_secret_key(secret_key: SecretKey) -> Wallet {
+        let public_key = PublicKey::from_secret_key(&secp256k1::Secp256k1::signing_only(), &secret_key);
+        return Wallet::from_keys(secret_key, public_key);
+    }
+
+    // the secret key in base58, anyone holding it can spend from this wallet
+    pub fn export_secret_key(&self) -> String {
+        return self.privatekey[..].to_base58();
+    }
+
+    pub fn import_secret_key(encoded: &str) -> Result<Wallet, WalletFileError> {
+        let secret_bytes = encoded.trim().from_base58().map_err(|_| WalletFileError::InvalidKey)?;
+        let secret_key = SecretKey::from_slice(&secret_bytes).map_err(|_| WalletFileError::InvalidKey)?;
+        return Ok(Wallet::from_secret_key(secret_key));
+    }
+
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<(), WalletFileError> {
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt).map_err(|_| WalletFileError::Corrupt)?;
+        rng.fill(&mut nonce).map_err(|_| WalletFileError::Corrupt)?;
+
+        let publickey = self.publickey.serialize();
+        let key = return_encryption_key(passphrase, PBKDF2_ITERATIONS, &salt);
+        let sealing_key = SealingKey::new(&CHACHA20_POLY1305, &key).map_err(|_| WalletFileError::Corrupt)?;
+
+        let mut sealed = self.privatekey[..].to_vec();
+        sealed.extend(vec![0; CHACHA20_POLY1305.tag_len()]);
+        aead::seal_in_place(&sealing_key, &nonce, &publickey, &mut sealed, CHACHA20_POLY1305.tag_len())
+            .map_err(|_| WalletFileError::Corrupt)?;
+
+        let mut file_bytes: Vec<u8> = Vec::new();
+        file_bytes.extend(WALLET_FILE_MAGIC);
+        file_bytes.write_u16::<BigEndian>(WALLET_FILE_VERSION)?;
+        file_bytes.write_u32::<BigEndian>(PBKDF2_ITERATIONS)?;
+        file_bytes.extend(&salt);
+        file_bytes.extend(&nonce);
+        file_bytes.extend(publickey.iter());
+        file_bytes.extend(&sealed);
+
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+
+        // never leave a half written wallet in place of a good one
+        let temp_path = path.with_extension("tmp");
+        let mut f = File::create(&temp_path)?;
+        f.write_all(&file_bytes[..])?;
+        f.sync_all()?;
+        rename(&temp_path, path)?;
+
+        return Ok(());
+    }
+
+    pub fn load(path: &Path, passphrase: &str) -> Result<Wallet, WalletFileError> {
+        let mut file_bytes = Vec::<u8>::new();
+        File::open(path)?.read_to_end(&mut file_bytes)?;
+
+        let mut reader = &file_bytes[..];
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(|_| WalletFileError::Corrupt)?;
+        if &magic != WALLET_FILE_MAGIC { return Err(WalletFileError::Corrupt); }
+
+        let version = reader.read_u16::<BigEndian>().map_err(|_| WalletFileError::Corrupt)?;
+        if version != WALLET_FILE_VERSION { return Err(WalletFileError::Corrupt); }
+
+        let iterations = reader.read_u32::<BigEndian>().map_err(|_| WalletFileError::Corrupt)?;
+        if iterations == 0 { return Err(WalletFileError::Corrupt); }
+
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        let mut publickey = [0u8; PUBLICKEY_LEN];
+        reader.read_exact(&mut salt).map_err(|_| WalletFileError::Corrupt)?;
+        reader.read_exact(&mut nonce).map_err(|_| WalletFileError::Corrupt)?;
+        reader.read_exact(&mut publickey).map_err(|_| WalletFileError::Corrupt)?;
+
+        let mut sealed = reader.to_vec();
+        if sealed.len() != KEY_LEN + CHACHA20_POLY1305.tag_len() { return Err(WalletFileError::Corrupt); }
+
+        let key = return_encryption_key(passphrase, iterations, &salt);
+        let opening_key = OpeningKey::new(&CHACHA20_POLY1305, &key).map_err(|_| WalletFileError::Corrupt)?;
+        let secret_bytes = aead::open_in_place(&opening_key, &nonce, &publickey, 0, &mut sealed)
+            .map_err(|_| WalletFileError::WrongPassphrase)?;
+
+        let secret_key = SecretKey::from_slice(secret_bytes).map_err(|_| WalletFileError::InvalidKey)?;
+        let wallet = Wallet::from_secret_key(secret_key);
+        if wallet.publickey.serialize()[..] != publickey[..] { return Err(WalletFileError::InvalidKey); }
+
+        return Ok(wallet);
+    }
+
+    // open the wallet at path, or create and save a new one if there is none
+    pub fn load_or_create(path: &Path, passphrase: &str) -> Result<Wallet, WalletFileError> {
+        if path.is_file() {
+            return Wallet::load(path, passphrase);
+        }
+
+        let wallet = Wallet::new();
+        wallet.save(path, passphrase)?;
+        return Ok(wallet);
+    }
+}
+
+fn return_encryption_key(passphrase: &str, iterations: u32, salt: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(&SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    return key;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wallet_file_round_trips_and_rejects_wrong_passphrase() {
+        let directory = std::env::temp_dir().join(format!("saito-wallet-test-{}", rand::random::<u64>()));
+        let path = directory.join("wallet.dat");
+
+        let wallet = Wallet::load_or_create(&path, "correct horse").unwrap();
+        let loaded_wallet = Wallet::load_or_create(&path, "correct horse").unwrap();
+        assert_eq!(loaded_wallet.return_publickey(), wallet.return_publickey());
+        assert_eq!(loaded_wallet.export_secret_key(), wallet.export_secret_key());
+
+        match Wallet::load(&path, "battery staple") {
+            Err(WalletFileError::WrongPassphrase) => {},
+            other => panic!("expected wrong passphrase, got {:?}", other.map(|w| w.return_base58())),
+        }
+
+        // a flipped bit in the publickey fails authentication as well
+        let mut file_bytes = std::fs::read(&path).unwrap();
+        file_bytes[40] ^= 1;
+        std::fs::write(&path, &file_bytes).unwrap();
+        assert!(Wallet::load(&path, "correct horse").is_err());
+
+        let imported_wallet = Wallet::import_secret_key(&wallet.export_secret_key()).unwrap();
+        assert_eq!(imported_wallet.return_base58(), wallet.return_base58());
+        assert!(Wallet::import_secret_key("not a key").is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -7,8 +7,7 @@ extern crate serde;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::cell::{RefCell, RefMut};
-use std::mem::transmute;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::fs::{File, read_dir};
 use std::io::{BufWriter, Read};
@@ -22,18 +21,61 @@ use digest::Digest;
 
 use ring::digest::{SHA256, Context};
 
-use secp256k1::{Secp256k1, Message, Signature};
+use secp256k1::{Secp256k1, Message, Signature, VerifyOnly};
 use secp256k1::{SecretKey, PublicKey};
 
 use merkle::{MerkleTree, Hashable};
 
-use rand::{Rng,thread_rng};
+use rand::thread_rng;
+
+use rayon::prelude::*;
 
 use base58::{ToBase58};
 //use byteorder::{BigEndian, ReadBytesExt};
 
+mod amount;
+mod coinselection;
+mod keystore;
+mod mining;
+mod network;
+mod payment;
+mod rpc;
+mod storage;
+mod sync;
+mod wire;
+pub use amount::{Amount, NOLAN_PER_SAITO};
+pub use coinselection::{BranchAndBound, CoinSelection, LargestFirst, OldestFirst, SmallestFirst, SpendPlan};
+pub use keystore::WalletFileError;
+pub use mining::{mine_golden_ticket, Miner, MiningStats, MiningStrategy, MiningTarget};
+pub use mining::{RandomStrategy, SeededStrategy, SequentialStrategy};
+pub use network::{Handshake, Network, NetworkEvent, PeerId, PeerMessage, PROTOCOL_VERSION};
+pub use payment::{PaymentBuilder, PaymentError};
+pub use rpc::{RpcError, RpcRequest, RpcServer};
+pub use storage::{BlockStore, BlockStoreEntry, RecoveryReport};
+pub use wire::WIRE_FORMAT_VERSION;
+
 static GENESIS_PERIOD: i32 = 21600;
 
+// paysplit is applied to amounts as an integer number of basis points
+const PAYSPLIT_BASIS_POINTS: u64 = 10000;
+
+// bytes of transactions a block may carry
+pub const MAX_BLOCK_SIZE: usize = 10_000_000;
+
+// default budget for transactions waiting on a block
+const MEMPOOL_MAX_BYTES: usize = 50_000_000;
+const MEMPOOL_MAX_TRANSACTIONS: usize = 100_000;
+
+// blocks held while we wait on their parent
+const MEMPOOL_MAX_BLOCKS: usize = 100;
+
+// how far ahead of our own clock a block may be stamped, in milliseconds
+const MAX_TIMESTAMP_DRIFT: u128 = 2000;
+
+// bodies of the latest blocks on the longest chain kept in memory, older
+// ones are read from the block store when they are asked for
+const MAX_BLOCKS_IN_MEMORY: usize = 100;
+
 fn time_since_unix_epoch() -> u128 {
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH)
@@ -41,6 +83,83 @@ fn time_since_unix_epoch() -> u128 {
     return since_the_epoch.as_millis();
 }
 
+// largest integer whose square is at most value, so consensus values never
+// depend on floating point rounding
+fn integer_sqrt(value: u64) -> u64 {
+    if value < 2 { return value; }
+
+    let mut x = value;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+    return x;
+}
+
+fn return_bit(bytes: &[u8], bit: usize) -> bool {
+    return bytes[bit / 8] & (0x80 >> (bit % 8)) != 0;
+}
+
+// a number below range drawn from the first 8 bytes of a hash, as if they
+// were a fraction between 0 and 1
+fn return_random_fraction(random_solution: &[u8], range: u128) -> u128 {
+    let mut bytes = [0u8; 8];
+    let len = random_solution.len().min(8);
+    bytes[..len].copy_from_slice(&random_solution[..len]);
+    return (u64::from_be_bytes(bytes) as u128 * range) >> 64;
+}
+
+// 2^-f of a 32 bit range for a fraction f counted in 1/65536ths, multiplied
+// up from repeated square roots of one half so every node agrees on it
+fn return_fraction_threshold(fraction: u64) -> u64 {
+    let mut threshold: u64 = 1 << 32;
+    let mut root: u64 = 1 << 31;
+    for bit in (0..16).rev() {
+        root = integer_sqrt(root << 32);
+        if fraction & (1 << bit) != 0 {
+            threshold = (threshold * root) >> 32;
+        }
+    }
+    return threshold;
+}
+
+// a solution is valid when it matches the hash of the block it solves for as
+// many bits as the difficulty asks for. Difficulty counts bytes, the whole
+// bits have to match exactly and whatever fraction of a bit is left over
+// narrows the range the 32 bits after them may fall in to 2^-fraction of it,
+// so every step of difficulty makes a solution a little harder to find
+// rather than 256x
+pub fn is_valid_solution(random_solution: &[u8], target: &[u8], difficulty: f32) -> bool {
+    if difficulty.is_nan() { return false; }
+
+    // bits in 1/65536ths, exact for any f32 so every node agrees
+    let difficulty_bits = (difficulty.max(0.0) as f64 * 8.0 * 65536.0).round() as u64;
+    let leading_bits = (difficulty_bits >> 16) as usize;
+    let fraction = difficulty_bits & 0xffff;
+
+    // nothing past the end of the hash can be matched
+    let len = random_solution.len().min(target.len());
+    if difficulty_bits > ((len * 8) as u64) << 16 { return false; }
+
+    let distance: Vec<u8> = random_solution.iter().zip(target.iter()).map(|(a, b)| a ^ b).collect();
+
+    for bit in 0..leading_bits {
+        if return_bit(&distance, bit) { return false; }
+    }
+
+    if fraction == 0 { return true; }
+
+    let mut next_bits: u64 = 0;
+    for bit in leading_bits..leading_bits + 32 {
+        next_bits <<= 1;
+        if bit < len * 8 && return_bit(&distance, bit) { next_bits |= 1; }
+    }
+
+    // from all of the range at no fraction towards half of it at a whole bit
+    return next_bits < return_fraction_threshold(fraction);
+}
+
 fn generate_keys() -> (SecretKey, PublicKey) {
     let secp = Secp256k1::new();
     return secp.generate_keypair(&mut thread_rng());
@@ -54,263 +173,394 @@ pub fn create_merkle_root(transactions: &Vec<Transaction>) -> Vec<u8> {
 }
 
 
-#[derive(Debug)]
-pub struct Miner {
-    is_mining: bool,
-    can_i_mine: bool,
-    difficulty: f32,
-    paysplit: f32,
+#[derive(Debug, Clone)]
+struct MempoolEntry {
+    transaction: Transaction,
+    fee: Amount,
+    size: usize,
 }
 
-impl Miner {
-    pub fn new() -> Miner {
-        return Miner {
-            is_mining: false,
-            can_i_mine: false,
-            difficulty: 2.0,
-            paysplit: 0.5,
+impl MempoolEntry {
+    fn new(transaction: Transaction) -> MempoolEntry {
+        return MempoolEntry {
+            fee: transaction.return_fees(),
+            size: transaction.return_size(),
+            transaction,
         };
     }
 
-    pub fn initialize(&mut self,
-                    mempool: &RefCell<Mempool>,
-                    blocks: &RefMut<Vec<Block>>,
-                    wallet: &Wallet,
-                    burnfee: &BurnFee) {
-        match blocks.last() {
-            Some(previous_block)=> {
-                self.start_mining(mempool, previous_block, wallet, burnfee);
-            }
-            None  => {}
-        }
+    // golden tickets pay out of the coinbase rather than fees but a block
+    // without one pays nobody, so they always go first. Everything else is
+    // ordered by fee per byte, compared without dividing
+    fn has_priority_over(&self, other: &MempoolEntry) -> bool {
+        let is_golden_ticket = self.transaction.tx_type == TransactionType::GoldenTicket;
+        let other_is_golden_ticket = other.transaction.tx_type == TransactionType::GoldenTicket;
+        if is_golden_ticket != other_is_golden_ticket { return is_golden_ticket; }
+
+        return self.fee.return_nolan() as u128 * other.size as u128
+            > other.fee.return_nolan() as u128 * self.size as u128;
+    }
+
+    fn return_sig_bytes(&self) -> Vec<u8> {
+        return self.transaction.sig.serialize_compact().to_vec();
     }
+}
 
-    pub fn start_mining(&mut self,
-                    mempool: &RefCell<Mempool>,
-                    previous_block: &Block,
-                    wallet: &Wallet,
-                    burnfee: &BurnFee) {
-        self.can_i_mine = true;
-        self.is_mining = true;
+// why a transaction was turned away from the mempool
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MempoolRejection {
+    Invalid,
+    InvalidSignature,
+    // an input is not an unspent slip on the longest chain
+    UnknownInput,
+    // an input is claimed by a pending transaction paying at least as much,
+    // or a golden ticket is already pending for the same block
+    Conflict,
+    // a golden ticket for a block that is no longer the tip, or inputs past
+    // the genesis period
+    Expired,
+    MempoolFull,
+}
 
-        while self.can_i_mine {
-            self.attempt_solution(mempool, previous_block, wallet, burnfee);
-        };
+// transactions waiting on a block, highest priority first
+#[derive(Debug)]
+pub struct Mempool {
+    blocks: RefCell<Vec<Block>>,
+    transactions: RefCell<Vec<MempoolEntry>>,
+    // input slip index to the signature of the pending transaction spending it
+    claimed: HashMap<Vec<u8>, Vec<u8>>,
+    bytes: usize,
+    max_bytes: usize,
+    max_transactions: usize,
+}
+
+impl Mempool {
+    pub fn new() -> RefCell<Mempool> {
+        return Mempool::with_limits(MEMPOOL_MAX_BYTES, MEMPOOL_MAX_TRANSACTIONS);
     }
 
-    pub fn stop_mining(&mut self) {
-        self.can_i_mine = false;
+    pub fn with_limits(max_bytes: usize, max_transactions: usize) -> RefCell<Mempool> {
+        return RefCell::new(Mempool{
+            blocks: RefCell::new(Vec::new()),
+            transactions: RefCell::new(Vec::new()),
+            claimed: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            max_transactions,
+        });
     }
 
-    // need previous_block, previous_block: &Block
-    fn attempt_solution(&mut self,
-                        mempool: &RefCell<Mempool>,
-                        previous_block: &Block,
-                        wallet: &Wallet,
-                        burnfee: &BurnFee) {
+    // a transaction spending inputs already claimed by pending transactions
+    // replaces them if it pays more in fees than all of them together
+    pub fn add_transaction(&mut self, tx: Transaction, blockchain: &Blockchain) -> Result<(), MempoolRejection> {
+        if !tx.validate() || tx.tx_type == TransactionType::Fee {
+            println!("REJECTING INVALID TRANSACTION");
+            return Err(MempoolRejection::Invalid);
+        }
+
+        if !tx.verify_signature() {
+            println!("REJECTING TRANSACTION WITH INVALID SIGNATURE");
+            return Err(MempoolRejection::InvalidSignature);
+        }
 
-        let mut rng = thread_rng();
-        let random_number = rng.gen::<u32>();
-        let random_number_bytes: [u8; 4] = unsafe { transmute(random_number.to_be()) };
+        if Mempool::is_expired(&tx, blockchain) {
+            println!("REJECTING EXPIRED TRANSACTION");
+            return Err(MempoolRejection::Expired);
+        }
 
-        // hash our solution
-        let mut hasher = Sha256::new();
-        let publickey_vec: Vec<u8> = wallet.publickey.serialize().iter().cloned().collect();
-        hasher.input(publickey_vec);
-        hasher.input(random_number_bytes);
-
-        let result = hasher.clone().result();
-        let random_solution_slice = result.as_slice();
-        let random_solution_vec = hasher.result().to_vec();
-
-        if self.is_valid_solution(&random_solution_vec, &previous_block.return_block_hash()) {
-            // Stop mining
-            //hasher.result().as_slice()
-            println!("WE HAVE FOUND A SOLUTION");
-            self.can_i_mine = false;
-
-            let golden_tx_solution = self.calculate_solution(
-                wallet.publickey,
-                &previous_block.return_block_hash(),
-                &random_solution_vec
-            );
-
-            // Find winning node
-            let winning_tx_address = self.find_winner(&random_solution_slice, &previous_block);
-
-            // we need to calculate the fees that are gonna go in the slips here
-            let paid_burn_fee = burnfee.return_previous_burnfee();
-
-            // This is just inputs - outputs for all transactions in the block
-            let total_fees_for_creator = previous_block.return_available_fees(&previous_block.creator);
-
-            // get the fees available from our publickey
-            let total_fees_in_block = previous_block.return_available_fees(&wallet.publickey);
-
-            // calculate the amount the creator can take for themselves
-            let creator_surplus = total_fees_for_creator - paid_burn_fee;
-
-            // find the amount that will be divied out to miners and nodes
-            let total_fees_for_miners_and_nodes = (total_fees_in_block - creator_surplus) + previous_block.coinbase;
-
-            // Calculate Shares
-            let miner_share = total_fees_for_miners_and_nodes * self.paysplit;
-            let node_share  = total_fees_for_miners_and_nodes - miner_share;
-
-            println!("CREAINTG GOLDEN TX");
-            //let mut golden_tx = Transaction::new(TransactionType::GoldenTicket);
-            let mut golden_tx = wallet.create_transaction(
-                wallet.publickey,
-                TransactionType::GoldenTicket,
-                0.001,
-                0.0
-            );
-
-            let mut golden_tx: Transaction = match wallet.create_transaction(
-                wallet.publickey,
-                TransactionType::GoldenTicket,
-                0.001,
-                0.0
-            ) {
-                Some(tx) => tx,
-                None => wallet.create_empty_golden_ticket(),
+        // a golden ticket is checked against the tip it solves, and only the
+        // first one to arrive for it is kept
+        if tx.tx_type == TransactionType::GoldenTicket {
+            let is_valid = match blockchain.blocks.borrow().last() {
+                Some(latest_block) => latest_block.validate_golden_ticket_transaction(&tx),
+                None => false,
             };
+            if !is_valid {
+                println!("REJECTING INVALID GOLDEN TICKET");
+                return Err(MempoolRejection::Invalid);
+            }
+            let target = Mempool::return_golden_ticket_target(&tx);
+            if self.transactions.borrow().iter().any(|entry| Mempool::return_golden_ticket_target(&entry.transaction) == target) {
+                println!("REJECTING SECOND GOLDEN TICKET FOR THE SAME BLOCK");
+                return Err(MempoolRejection::Conflict);
+            }
+        }
+
+        // empty slips such as those on golden tickets spend nothing
+        let inputs: Vec<&Slip> = tx.from.iter().filter(|slip| !slip.amount.is_zero()).collect();
+        if inputs.iter().any(|slip| !blockchain.is_slip_spendable(slip)) {
+            println!("REJECTING TRANSACTION SPENDING UNKNOWN OR SPENT SLIP");
+            return Err(MempoolRejection::UnknownInput);
+        }
 
-            golden_tx.add_to_slip(Slip {
-                address: wallet.publickey,
-                amount: miner_share,
-                block_id: 0,
-                transaction_id: 0,
-                id: 0,
-                block_hash: Vec::new(),
-            });
+        let mut conflicts: Vec<Vec<u8>> = Vec::new();
+        for slip in inputs.iter() {
+            if let Some(sig) = self.claimed.get(&slip.return_index()) {
+                if !conflicts.contains(sig) { conflicts.push(sig.clone()); }
+            }
+        }
 
-            golden_tx.add_to_slip(Slip {
-                address: winning_tx_address,
-                amount: node_share,
-                block_id: 0,
-                transaction_id: 0,
-                id: 0,
-                block_hash: Vec::new(),
-            });
+        let entry = MempoolEntry::new(tx);
 
-            // sign TX
-            golden_tx.sig = wallet.create_signature(golden_tx.return_signature_source().as_slice());
+        if !conflicts.is_empty() {
+            let conflicting_fees = Amount::checked_sum(
+                self.transactions.borrow().iter()
+                    .filter(|existing| conflicts.contains(&existing.return_sig_bytes()))
+                    .map(|existing| existing.fee)
+            ).unwrap_or(Amount::from_nolan(u64::max_value()));
 
-            mempool.borrow_mut().add_transaction(golden_tx);
-        }
-    }
+            if entry.fee <= conflicting_fees {
+                println!("REJECTING TRANSACTION CONFLICTING WITH PENDING TRANSACTION");
+                return Err(MempoolRejection::Conflict);
+            }
+
+            println!("REPLACING {} PENDING TRANSACTIONS BY FEE", conflicts.len());
+            let replaced = self.remove_entries(|existing| conflicts.contains(&existing.return_sig_bytes()));
 
-    fn calculate_solution(&self, publickey: PublicKey, block_hash: &Vec<u8>, random: &Vec<u8>) -> GoldenTicket {
-        let mut vote: u8 = 0;
-//        if block.difficulty > self.difficulty {
-//            vote = 1;
-//        }
-        return GoldenTicket {
-            target: block_hash.clone(),
-            vote: 1,
-            random: random.clone(),
-            publickey,
+            // a replacement the mempool has no room for leaves the
+            // transactions it conflicted with where they were, they fit
+            // before so they fit again
+            let result = self.insert_entry(entry);
+            if result.is_err() {
+                for replaced_entry in replaced {
+                    let _ = self.insert_entry(replaced_entry);
+                }
+            }
+            return result;
         }
+
+        return self.insert_entry(entry);
     }
 
-    fn is_valid_solution(&self, random_solution: &Vec<u8>, prev_blk: &Vec<u8>) -> bool {
-        // static difficulty until this is implemented on the block object
-        let difficulty = self.difficulty.round() as usize;
+    fn return_golden_ticket_target(tx: &Transaction) -> Option<Vec<u8>> {
+        if tx.tx_type != TransactionType::GoldenTicket { return None; }
+        return GoldenTicket::from_bytes(&tx.msg).ok().map(|golden_ticket| golden_ticket.target);
+    }
 
-        let random_solution_slice = &random_solution[0..difficulty];
-        let previous_hash_slice = &prev_blk[0..difficulty];
+    fn is_expired(tx: &Transaction, blockchain: &Blockchain) -> bool {
+        if tx.tx_type == TransactionType::GoldenTicket {
+            return match GoldenTicket::from_bytes(&tx.msg) {
+                Ok(golden_ticket) => golden_ticket.target != blockchain.return_latest_hash(),
+                Err(_) => true,
+            };
+        }
 
-        //println!("RANDOM SOLUTION {}", random_solution_slice);
-        //println!("PREVIOUS HASH SLICE {}", random_solution_slice);
+        let lower_block_limit: i64 = blockchain.return_last_block_id() as i64 + 1 - GENESIS_PERIOD as i64;
+        return tx.from.iter().any(|slip| !slip.amount.is_zero() && (slip.block_id as i64) < lower_block_limit);
+    }
 
-        if random_solution_slice == previous_hash_slice {
-            return true
-        } else {
-            return false;
+    // puts the transactions of blocks unwound off the longest chain back in
+    // line for the next block, anything the new chain has spent, or that no
+    // longer makes sense on it, is dropped
+    pub fn reinject_transactions(&mut self, blocks: &Vec<Block>, blockchain: &Blockchain) {
+        let mut reinjected = 0;
+        for block in blocks.iter() {
+            for tx in block.transactions.iter() {
+                if self.add_transaction(tx.clone(), blockchain).is_ok() {
+                    reinjected += 1;
+                }
+            }
         }
+        println!("REINJECTED {} TRANSACTIONS INTO MEMPOOL", reinjected);
+        self.revalidate(blockchain);
     }
 
-    fn find_winner(&self, random_solution: &[u8], previous_block: &Block) -> PublicKey {
-       // let max_hash = 0xFFFFFFFF;
+    // drops whatever the longest chain has made invalid since it was admitted
+    pub fn revalidate(&mut self, blockchain: &Blockchain) {
+        self.remove_entries(|entry| {
+            Mempool::is_expired(&entry.transaction, blockchain)
+                || entry.transaction.from.iter()
+                    .any(|slip| !slip.amount.is_zero() && !blockchain.is_slip_spendable(slip))
+        });
+    }
+
+    // holds a block whose parent we have not seen, the oldest is dropped to
+    // make room
+    pub fn add_block(&mut self, block: Block) {
+        let mut blocks = self.blocks.borrow_mut();
+        if blocks.iter().any(|held| held.return_block_hash() == block.return_block_hash()) { return; }
+        if blocks.len() >= MEMPOOL_MAX_BLOCKS {
+            blocks.remove(0);
+        }
+        blocks.push(block);
+    }
 
-       let winning_address: PublicKey;
-       if previous_block.transactions.len() == 0 { return previous_block.creator; }
+    pub fn take_blocks_waiting_on(&mut self, block_hash: &Vec<u8>) -> Vec<Block> {
+        let mut blocks = self.blocks.borrow_mut();
+        let (waiting, held): (Vec<Block>, Vec<Block>) = blocks.drain(..)
+            .partition(|block| &block.previous_hash == block_hash);
+        *blocks = held;
+        return waiting;
+    }
 
-       //let random_slice = random_solution;
+    pub fn return_block_length(&self) -> u32 {
+        return self.blocks.borrow().len() as u32;
+    }
 
-       //let max_num: u32 = u32::from_str_radix("ffffffffffff", 16).unwrap();
-       //let win_num: u32 = unsafe { transmute(random_solution.to_be()) };
-       //let win_num = random_solution.clone().read_u32::<BigEndian>().unwrap(); //(random_solution);
+    // adds a block to the chain, or holds it until its parent arrives, then
+    // connects whatever was waiting on it and keeps the mempool in step
+    // with the longest chain
+    pub fn receive_block(&mut self, block: Block, blockchain: &mut Blockchain, wallet: &mut Wallet) -> AddBlockResult {
+        let mut result: Option<AddBlockResult> = None;
+        let mut pending: Vec<Block> = vec![block];
 
-       //let winner_dec = win_num as f32 / max_num as f32;
+        while let Some(block) = pending.pop() {
+            let block_hash = block.return_block_hash();
 
-       let mut winning_tx = self.find_winning_transaction(previous_block);
+            let block_result = blockchain.add_block(block.clone(), wallet);
 
-       // until we differentiate between fees and amount, we'll just use amount
+            match &block_result {
+                AddBlockResult::Orphan => {
+                    self.add_block(block);
+                },
+                AddBlockResult::Extended | AddBlockResult::Reorganized(_) => {
+                    if let AddBlockResult::Reorganized(unwound_blocks) = &block_result {
+                        self.reinject_transactions(unwound_blocks, blockchain);
+                    }
+                    // a reorganization can stop short of the block at an
+                    // invalid ancestor
+                    if blockchain.index.get(&block_hash).map_or(false, |block_index| block_index.lc) {
+                        self.remove_transactions(&block.transactions);
+                    }
+                    self.revalidate(blockchain);
+                    pending.extend(self.take_blocks_waiting_on(&block_hash));
+                },
+                AddBlockResult::Fork => {
+                    pending.extend(self.take_blocks_waiting_on(&block_hash));
+                },
+                _ => {},
+            }
 
-       winning_address = match winning_tx {
-          Some(tx) => tx.from[0].address,
-          None => previous_block.creator
-       };
+            if result.is_none() {
+                result = Some(block_result);
+            }
+        }
 
-       return winning_address;
+        wallet.sync_pending(self.transactions.borrow().iter().map(|entry| &entry.transaction));
 
-       //return winning_tx.from[0].address;
+        return result.unwrap();
     }
 
-    fn find_winning_transaction(&self, previous_block: &Block) -> Option<Transaction> {
-        let mut winning_tx = Transaction::new(TransactionType::Base);
-        let mut winning_amt = 0.0;
-        for tx in previous_block.transactions.iter() {
-            let current_amt = tx.calculate_from_amount();
-            if winning_amt < current_amt {
-               winning_tx = tx.clone();
-               winning_amt = current_amt;
-           }
+    fn insert_entry(&mut self, entry: MempoolEntry) -> Result<(), MempoolRejection> {
+        let mut transactions = self.transactions.borrow_mut();
+
+        // make room by evicting whatever pays less than the new transaction,
+        // and leave the mempool untouched if that is not enough
+        let mut count = transactions.len();
+        let mut bytes = self.bytes;
+        while count >= self.max_transactions || bytes + entry.size > self.max_bytes {
+            if count == 0 || !entry.has_priority_over(&transactions[count - 1]) {
+                println!("MEMPOOL FULL, REJECTING TRANSACTION");
+                return Err(MempoolRejection::MempoolFull);
+            }
+            count -= 1;
+            bytes -= transactions[count].size;
         }
-        if winning_amt == 0.0 {
-            return None
-        } else {
-            return Some(winning_tx.clone());
+
+        if count < transactions.len() {
+            println!("EVICTING {} TRANSACTIONS FROM MEMPOOL", transactions.len() - count);
+            for evicted in transactions.drain(count..) {
+                release_slips(&mut self.claimed, &evicted);
+            }
+        }
+
+        for slip in entry.transaction.from.iter().filter(|slip| !slip.amount.is_zero()) {
+            self.claimed.insert(slip.return_index(), entry.return_sig_bytes());
         }
+
+        // ties keep the order they arrived in
+        let position = transactions.iter()
+            .position(|existing| entry.has_priority_over(existing))
+            .unwrap_or(transactions.len());
+        self.bytes = bytes + entry.size;
+        transactions.insert(position, entry);
+        return Ok(());
+    }
+
+    // returns what was removed
+    fn remove_entries<F: Fn(&MempoolEntry) -> bool>(&mut self, should_remove: F) -> Vec<MempoolEntry> {
+        let mut transactions = self.transactions.borrow_mut();
+        let mut kept: Vec<MempoolEntry> = Vec::new();
+        let mut removed: Vec<MempoolEntry> = Vec::new();
+        for entry in transactions.drain(..) {
+            if should_remove(&entry) {
+                release_slips(&mut self.claimed, &entry);
+                removed.push(entry);
+            } else {
+                kept.push(entry);
+            }
+        }
+        self.bytes = kept.iter().map(|entry| entry.size).sum();
+        *transactions = kept;
+        return removed;
     }
-}
 
+    // highest priority first, which is the order blocks are filled in
+    pub fn return_transactions(&self) -> Vec<Transaction> {
+        return self.transactions.borrow().iter().map(|entry| entry.transaction.clone()).collect();
+    }
 
-#[derive(Debug)]
-pub struct Mempool {
-    blocks: RefCell<Vec<Block>>,
-    transactions: RefCell<Vec<Transaction>>,
-}
+    pub fn return_transaction_length(&self) -> u32 {
+        return self.transactions.borrow_mut().len() as u32;
+    }
 
-impl Mempool {
-    pub fn new() -> RefCell<Mempool> {
-        return RefCell::new(Mempool{
-            blocks: RefCell::new(Vec::new()),
-            transactions: RefCell::new(Vec::new())
-        });
+    pub fn return_size(&self) -> usize {
+        return self.bytes;
     }
 
-    pub fn add_transaction(&mut self, tx: Transaction) {
-        self.transactions.borrow_mut().push(tx);
+    pub fn is_slip_claimed(&self, slip: &Slip) -> bool {
+        return self.claimed.contains_key(&slip.return_index());
     }
 
-    pub fn return_transactions(&self) -> Vec<Transaction> {
-        return self.transactions.borrow_mut().clone();
+    // looks a transaction up by its compact signature
+    pub fn return_transaction(&self, sig: &[u8]) -> Option<Transaction> {
+        return self.transactions.borrow().iter()
+            .find(|entry| &entry.return_sig_bytes()[..] == sig)
+            .map(|entry| entry.transaction.clone());
     }
 
-    pub fn return_transaction_length(&self) -> u32 {
-        return self.transactions.borrow_mut().len() as u32;
+    pub fn contains_transaction(&self, sig: &[u8]) -> bool {
+        return self.return_transaction(sig).is_some();
+    }
+
+    pub fn is_block_held(&self, block_hash: &Vec<u8>) -> bool {
+        return self.blocks.borrow().iter().any(|block| &block.return_block_hash() == block_hash);
+    }
+
+    // drops whatever made it into a block, along with anything spending the
+    // same inputs, and keeps the rest for the next one
+    pub fn remove_transactions(&mut self, transactions: &Vec<Transaction>) {
+        let spent: HashMap<Vec<u8>, u8> = transactions.iter()
+            .flat_map(|tx| tx.from.iter())
+            .filter(|slip| !slip.amount.is_zero())
+            .map(|slip| (slip.return_index(), 0))
+            .collect();
+
+        self.remove_entries(|entry| {
+            transactions.iter().any(|tx| tx.sig == entry.transaction.sig)
+                || entry.transaction.from.iter().any(|slip| spent.contains_key(&slip.return_index()))
+        });
     }
 
     pub fn clear_tx_mempool(&mut self) {
         self.transactions = RefCell::new(Vec::new());
+        self.claimed = HashMap::new();
+        self.bytes = 0;
     }
 }
 
-#[derive(Debug)]
-struct GoldenTicket {
+fn release_slips(claimed: &mut HashMap<Vec<u8>, Vec<u8>>, entry: &MempoolEntry) {
+    for slip in entry.transaction.from.iter() {
+        if claimed.get(&slip.return_index()) == Some(&entry.return_sig_bytes()) {
+            claimed.remove(&slip.return_index());
+        }
+    }
+}
+
+// a solution to the previous block, carried in the msg of the golden ticket
+// transaction that claims its payout
+#[derive(PartialEq, Debug, Clone)]
+pub struct GoldenTicket {
     target: Vec<u8>,
     vote: u8,
     random: Vec<u8>,
@@ -318,18 +568,31 @@ struct GoldenTicket {
 }
 
 impl GoldenTicket {
+    // anyone can rehash the ticket to check the miner's work, the vote is
+    // hashed along with it so it can not be changed without mining again
+    pub fn return_solution(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(self.publickey.serialize().to_vec());
+        hasher.input(&[self.vote]);
+        hasher.input(&self.random);
+        return hasher.result().to_vec();
+    }
+
     fn calculate_difficulty (&self, previous_block: &Block) -> f32 {
-        return match self.vote {
+        let difficulty = match self.vote {
             1 => previous_block.difficulty + 0.01,
             _ => previous_block.difficulty - 0.01
-        }
+        };
+        return difficulty.max(0.0);
     }
 
+    // the paysplit is a fraction of the payout so it stays between 0 and 1
     fn calculate_paysplit (&self, previous_block: &Block) -> f32 {
-        return match self.vote {
+        let paysplit = match self.vote {
             1 => previous_block.paysplit + 0.01,
             _ => previous_block.paysplit - 0.01
-        }
+        };
+        return paysplit.max(0.0).min(1.0);
     }
 }
 
@@ -361,6 +624,10 @@ pub struct Transaction {
 
     #[serde(with = "serde_bytes")]
     pub msg: Vec<u8>,
+
+    // the nodes the transaction passed through on its way to us, appended
+    // to after signing so it is not covered by sig
+    path: Vec<Hop>,
 }
 
 impl Transaction {
@@ -372,15 +639,13 @@ impl Transaction {
             sig: Signature::from_compact(&[0; 64]).unwrap(),
             to: Vec::new(),
             from: Vec::new(),
-            msg: Vec::new()
+            msg: Vec::new(),
+            path: Vec::new(),
         };
     }
 
     pub fn validate(&self) -> bool {
         // we'll leave validation of Transaction inputs for the blockchain here
-        let mut total_to_amount: f32 = 0.0;
-        let mut total_from_amount: f32 = 0.0;
-
         // we need min one sender and one receiver
         if self.from.len() < 1 { 
             return false; 
@@ -390,23 +655,18 @@ impl Transaction {
             return false; 
         }
 
-        // check for negative slips 
-        for slip in self.from.iter() { 
-            if slip.amount < 0.0 { 
-                return false; 
-            } 
-            total_from_amount += slip.amount;
-        }
-        for slip in self.to.iter() { 
-            if slip.amount < 0.0 { 
-                return false; 
-            } 
-            total_to_amount += slip.amount;
-        }
+        // amounts cannot be negative, but their sums can overflow
+        let total_from_amount = match Amount::checked_sum(self.from.iter().map(|slip| slip.amount)) {
+            Some(total_from_amount) => total_from_amount,
+            None => return false,
+        };
+        let total_to_amount = match Amount::checked_sum(self.to.iter().map(|slip| slip.amount)) {
+            Some(total_to_amount) => total_to_amount,
+            None => return false,
+        };
 
         match self.tx_type {
             TransactionType::GoldenTicket => {},
-            TransactionType::Fee => {},
             _ => { 
                 if total_to_amount > total_from_amount { 
                     return false; 
@@ -425,59 +685,147 @@ impl Transaction {
         self.from.push(slip)
     }
 
-    fn calculate_from_amount(&self) -> f32 {
-        let mut total_amount: f32 = 0.0;
-        for slip in self.from.clone().into_iter() {
-           total_amount += slip.amount;
-        }
-        return total_amount;
+    fn calculate_from_amount(&self) -> Amount {
+        return Amount::checked_sum(self.from.iter().map(|slip| slip.amount)).unwrap_or(Amount::zero());
     }
 
-    fn calculate_to_amount(&self) -> f32 {
-        let mut total_amount: f32 = 0.0;
-        for slip in self.to.clone().into_iter() {
-           total_amount += slip.amount;
-        }
-        return total_amount;
+    fn calculate_to_amount(&self) -> Amount {
+        return Amount::checked_sum(self.to.iter().map(|slip| slip.amount)).unwrap_or(Amount::zero());
     }
 
-    fn return_fees_usable(&self, key: &PublicKey) -> f32 {
-        let mut input_fees: f32 = 0.0;
-        let mut output_fees: f32 = 0.0;
+    // whatever the inputs pay that the outputs do not take
+    pub fn return_fees(&self) -> Amount {
+        return self.calculate_from_amount().saturating_sub(self.calculate_to_amount());
+    }
 
-        for slip in self.from.iter() {
-            if &slip.address == key {
-                input_fees += slip.amount;
-            }
-        }
+    // a transaction that pays the key more than it takes from it has no
+    // fees usable by that key
+    fn return_fees_usable(&self, key: &PublicKey) -> Amount {
+        let input_fees = Amount::checked_sum(
+            self.from.iter().filter(|slip| &slip.address == key).map(|slip| slip.amount)
+        ).unwrap_or(Amount::zero());
 
-        for slip in self.to.iter() {
-            if &slip.address == key {
-                output_fees += slip.amount;
-            }
-        }
+        let output_fees = Amount::checked_sum(
+            self.to.iter().filter(|slip| &slip.address == key).map(|slip| slip.amount)
+        ).unwrap_or(Amount::zero());
 
-        return input_fees - output_fees;
+        return input_fees.saturating_sub(output_fees);
     }
 
 
     // duplicate minus id, evaluate
     pub fn return_signature_source(&self) -> Vec<u8> {
         let mut sig_source_bytes: Vec<u8> = Vec::new();
-        let timestamp_bytes: [u8; 16] = unsafe { transmute(self.timestamp.to_be()) };
-
-        sig_source_bytes.extend(&timestamp_bytes);
+        sig_source_bytes.push(self.tx_type.to_byte());
+        sig_source_bytes.extend(&self.timestamp.to_be_bytes());
 
         for slip in self.from.iter() {
             sig_source_bytes.extend(slip.return_index());
         }
 
+        // output slips are only assigned their ids once bundled into a
+        // block, so the signature covers who gets paid and how much
         for slip in self.to.iter() {
-            sig_source_bytes.extend(slip.return_index());
+            sig_source_bytes.extend(slip.return_output_source());
         }
+
+        // the msg carries the golden ticket and its vote
+        wire::write_bytes(&mut sig_source_bytes, &self.msg);
         return sig_source_bytes;
     }
 
+    // every input slip must belong to the key that signed the transaction
+    pub fn verify_signature(&self) -> bool {
+        let secp = Secp256k1::verification_only();
+        return self.verify_signature_with(&secp);
+    }
+
+    fn verify_signature_with(&self, secp: &Secp256k1<VerifyOnly>) -> bool {
+        let signer = match self.from.first() {
+            Some(slip) => slip.address,
+            None => return false,
+        };
+
+        if self.from.iter().any(|slip| slip.address != signer) {
+            return false;
+        }
+
+        let msg = match Message::from_slice(self.return_message_hash().as_slice()) {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+
+        if !secp.verify(&msg, &self.sig, &signer).is_ok() { return false; }
+
+        return self.verify_path_with(secp);
+    }
+
+    pub fn return_path(&self) -> &Vec<Hop> {
+        return &self.path;
+    }
+
+    // whoever holds the transaction now, the sender until it is relayed
+    fn return_path_end(&self) -> Option<PublicKey> {
+        return match self.path.last() {
+            Some(hop) => Some(hop.to),
+            None => self.from.first().map(|slip| slip.address),
+        };
+    }
+
+    // sign the transaction over to the next node, only the node at the end
+    // of the path can do this
+    pub fn add_hop(&mut self, wallet: &Wallet, to: PublicKey) -> bool {
+        if self.return_path_end() != Some(wallet.return_publickey()) { return false; }
+
+        let sig = wallet.create_signature(&Hop::return_signature_source(&self.sig, &to));
+        self.path.push(Hop { from: wallet.return_publickey(), to, sig });
+        return true;
+    }
+
+    // the path starts at the sender, every hop picks up where the last one
+    // left off and is signed by the node that made it
+    pub fn verify_path(&self) -> bool {
+        let secp = Secp256k1::verification_only();
+        return self.verify_path_with(&secp);
+    }
+
+    fn verify_path_with(&self, secp: &Secp256k1<VerifyOnly>) -> bool {
+        let mut holder = match self.from.first() {
+            Some(slip) => slip.address,
+            None => return self.path.is_empty(),
+        };
+
+        for hop in self.path.iter() {
+            if hop.from != holder { return false; }
+
+            let mut hasher = Sha256::new();
+            hasher.input(Hop::return_signature_source(&self.sig, &hop.to));
+            let msg = match Message::from_slice(hasher.result().as_slice()) {
+                Ok(msg) => msg,
+                Err(_) => return false,
+            };
+            if !secp.verify(&msg, &hop.sig, &hop.from).is_ok() { return false; }
+
+            holder = hop.to;
+        }
+        return true;
+    }
+
+    // the fee counts in full for the first hop and halves with every hop
+    // after it, so a transaction is worth the most to the nodes closest to
+    // its sender
+    pub fn return_routing_work(&self) -> Amount {
+        let halvings = self.path.len().saturating_sub(1);
+        if halvings >= 64 { return Amount::zero(); }
+        return self.return_fees().checked_div(1 << halvings).unwrap();
+    }
+
+    // routing work only counts for the node the path ends at
+    pub fn return_routing_work_for(&self, publickey: &PublicKey) -> Amount {
+        if self.return_path_end() != Some(*publickey) { return Amount::zero(); }
+        return self.return_routing_work();
+    }
+
     pub fn return_message_hash(&self) -> Vec<u8> {
         let mut hasher = Sha256::new();
         hasher.input(self.return_signature_source());
@@ -486,10 +834,8 @@ impl Transaction {
 
     pub fn return_message_signature_source(&self) -> Vec<u8> {
         let mut message_sig_source: Vec<u8> = Vec::new();
-        let timestamp_bytes: [u8; 16] = unsafe { transmute(self.timestamp.to_be()) };
-
         message_sig_source.extend(&self.msg);
-        message_sig_source.extend(&timestamp_bytes);
+        message_sig_source.extend(&self.timestamp.to_be_bytes());
 
         return message_sig_source;
     }
@@ -504,22 +850,58 @@ impl Clone for Transaction {
             sig: self.sig,
             to: self.to.clone(),
             from: self.from.clone(),
-            msg: self.msg.clone()
+            msg: self.msg.clone(),
+            path: self.path.clone(),
         }
     }
 }
 
 // finish Hashable for Transaction
+//
+// a leaf commits to the signed transaction, its ids and its routing path,
+// the slip fields update_slips fills in are checked by validation instead
 impl Hashable for Transaction {
     fn update_context(&self, context: &mut Context) {
         context.update(&self.return_signature_source());
+        context.update(&self.id.to_be_bytes());
+        for slip in self.to.iter() {
+            context.update(&slip.id.to_be_bytes());
+        }
+        for hop in self.path.iter() {
+            context.update(&hop.to_bytes());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Hop {
+    from: PublicKey,
+    to: PublicKey,
+    sig: Signature,
+}
+
+impl Hop {
+    pub fn return_from(&self) -> PublicKey {
+        return self.from;
+    }
+
+    pub fn return_to(&self) -> PublicKey {
+        return self.to;
+    }
+
+    // a hop signs over the transaction signature and the next node
+    fn return_signature_source(tx_sig: &Signature, to: &PublicKey) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(tx_sig.serialize_compact().iter());
+        bytes.extend(to.serialize().iter());
+        return bytes;
     }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Slip {
     address: PublicKey,
-    amount: f32,
+    amount: Amount,
     block_id: u32,
     transaction_id: u32,
     id: u32,
@@ -532,7 +914,7 @@ impl Slip {
     pub fn new(publickey: PublicKey) -> Slip {
         return Slip {
             address: publickey,
-            amount: 0.0,
+            amount: Amount::zero(),
             block_id: 0,
             transaction_id: 0,
             id: 0,
@@ -541,43 +923,58 @@ impl Slip {
     } 
     
     pub fn return_index(&self) -> Vec<u8> {
-        //let mut bytes: Vec<u8> = Vec::new();
-        //let address_bytes: Vec<u8> = self.address.serialize().into_iter().cloned().collect();
-        //let amount_bytes: [u8; 4] = unsafe { transmute(self.amount as u32) };
-        //let block_id_bytes : [u8; 4] = unsafe { transmute(self.block_id.to_be()) };
-        //let transaction_id_bytes : [u8; 4] = unsafe { transmute(self.transaction_id.to_be()) };
-        //let slip_id_bytes : [u8; 4] = unsafe { transmute(self.id.to_be()) };
-
-        ////bytes.extend(&address_bytes);
-        //for elem in address_bytes.iter() { bytes.push(*elem); }
-        //for elem in amount_bytes.iter() { bytes.push(*elem) }
-        //for elem in block_id_bytes.iter() { bytes.push(*elem); }
-        //for elem in transaction_id_bytes.iter() { bytes.push(*elem); }
-        //for elem in slip_id_bytes.iter() { bytes.push(*elem); }
-        //for elem in self.block_hash.iter() { bytes.push(*elem); }
-
-        //return bytes;
-        return bincode::serialize(self).unwrap();
+        return self.to_bytes();
     }
+
+    fn return_output_source(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(self.address.serialize().iter());
+        bytes.extend(&self.amount.return_nolan().to_be_bytes());
+        return bytes;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SlipState {
+    Unspent,
+    Pending,
+    Spent,
 }
 
+// every slip the wallet owns is in exactly one state, keyed by slip index
+//
+// inputs holds the unspent slips on the longest chain, spends those of them
+// used by a transaction still sitting in the mempool, and outputs the slips
+// spent on the longest chain. unwinding a block returns its spent slips to
+// inputs and forgets the slips it created
 pub struct Wallet {
     publickey: PublicKey,
     privatekey: SecretKey,
     inputs: HashMap<Vec<u8>, Slip>,
     outputs: HashMap<Vec<u8>, Slip>,
-    spends: HashMap<Vec<u8>, Slip>
+    spends: HashMap<Vec<u8>, Slip>,
+
+    // what our pending transactions pay back to us, keyed by signature
+    receipts: HashMap<Vec<u8>, Amount>,
+
+    coin_selection: Box<dyn CoinSelection>,
 }
 
 impl Wallet {
     pub fn new() -> Wallet {
         let (secret_key, public_key) = generate_keys();
+        return Wallet::from_keys(secret_key, public_key);
+    }
+
+    fn from_keys(secret_key: SecretKey, public_key: PublicKey) -> Wallet {
         return Wallet {
             publickey: public_key,
             privatekey: secret_key,
             inputs: HashMap::new(),
             outputs: HashMap::new(),
-            spends: HashMap::new()
+            spends: HashMap::new(),
+            receipts: HashMap::new(),
+            coin_selection: Box::new(OldestFirst),
         };
     }
 
@@ -608,12 +1005,13 @@ impl Wallet {
 
     pub fn process_payment(&mut self, transactions: &Vec<Transaction>) {
         for tx in transactions.iter() {
+            self.receipts.remove(&tx.sig.serialize_compact().to_vec());
+
             for slip in tx.from.iter() {
                 if slip.address == self.publickey  {
+                    self.inputs.remove(&slip.return_index());
+                    self.spends.remove(&slip.return_index());
                     self.outputs.insert(slip.return_index(), slip.clone());
-                    if self.spends.contains_key(&slip.return_index())  {
-                        self.spends.remove(&slip.return_index());
-                    }
                 }
             }
 
@@ -625,40 +1023,121 @@ impl Wallet {
         }
     }
 
-    pub fn return_balance(&self) -> f32 {
-        let mut balance: f32 = 0.0;
-        for (_, slip) in self.inputs.clone() {
-            balance += slip.amount;
+    // undo process_payment for the transactions of a block that has been
+    // unwound off the longest chain
+    pub fn rollback_payment(&mut self, transactions: &Vec<Transaction>) {
+        // newest first, so a slip created and spent in the same block ends
+        // up forgotten rather than unspent
+        for tx in transactions.iter().rev() {
+            for slip in tx.to.iter() {
+                if slip.address == self.publickey {
+                    self.inputs.remove(&slip.return_index());
+                    self.spends.remove(&slip.return_index());
+                }
+            }
+
+            for slip in tx.from.iter() {
+                if slip.address == self.publickey {
+                    self.outputs.remove(&slip.return_index());
+                    self.inputs.insert(slip.return_index(), slip.clone());
+                }
+            }
+        }
+    }
+
+    // a transaction of ours has been accepted into the mempool, so its
+    // inputs are no longer available to the next one we create
+    pub fn mark_pending(&mut self, tx: &Transaction) {
+        let mut is_ours = false;
+        for slip in tx.from.iter() {
+            if let Some(input) = self.inputs.get(&slip.return_index()) {
+                self.spends.insert(slip.return_index(), input.clone());
+                is_ours = true;
+            }
+        }
+
+        if is_ours {
+            let receipts = tx.to.iter()
+                .filter(|slip| slip.address == self.publickey)
+                .map(|slip| slip.amount);
+            self.receipts.insert(tx.sig.serialize_compact().to_vec(), Amount::checked_sum(receipts).unwrap_or_default());
+        }
+    }
+
+    // rebuild the pending state from what is actually in the mempool, which
+    // releases the inputs of transactions it has dropped and picks up those
+    // it has reinjected after a reorganization
+    pub fn sync_pending<'a, I: Iterator<Item = &'a Transaction>>(&mut self, transactions: I) {
+        self.spends = HashMap::new();
+        self.receipts = HashMap::new();
+        for tx in transactions {
+            self.mark_pending(tx);
         }
+    }
+
+    pub fn return_slip_state(&self, slip: &Slip) -> Option<SlipState> {
+        let index = slip.return_index();
+        if self.spends.contains_key(&index) { return Some(SlipState::Pending); }
+        if self.inputs.contains_key(&index) { return Some(SlipState::Unspent); }
+        if self.outputs.contains_key(&index) { return Some(SlipState::Spent); }
+        return None;
+    }
+
+    // everything unspent on the longest chain, including pending spends
+    pub fn return_balance(&self) -> Amount {
+        return Amount::checked_sum(self.inputs.values().map(|slip| slip.amount)).unwrap();
+    }
 
-        return balance;
+    // what we can spend right now without double spending the mempool
+    pub fn return_available_balance(&self) -> Amount {
+        let pending = Amount::checked_sum(self.spends.values().map(|slip| slip.amount)).unwrap();
+        return self.return_balance().saturating_sub(pending);
     }
 
-    pub fn create_transaction(&self, publickey: PublicKey, tx_type: TransactionType, fee: f32, amt: f32) -> Option<Transaction> {
-       let total = fee + amt;
-       let from_slips = self.return_available_inputs(total);
+    // what we will hold once our pending transactions are in a block
+    pub fn return_pending_balance(&self) -> Amount {
+        let receipts = Amount::checked_sum(self.receipts.values().cloned()).unwrap();
+        return self.return_available_balance().checked_add(receipts).unwrap();
+    }
 
-       match from_slips {
-           Some(slips) => {
-               let from_amt: f32 = slips.iter().map(|slip| slip.amount).sum();
-               let to_recover_amt = from_amt - total;
+    // start a signed payment to one or more recipients
+    pub fn create_payment(&mut self) -> PaymentBuilder<'_> {
+        return PaymentBuilder::new(self);
+    }
 
-               let mut to_slip = Slip::new(publickey);
-               to_slip.amount = to_recover_amt;
+    // an unsigned transaction paying amt to publickey with the change back
+    // to us, for callers that add their own slips before signing it
+    pub fn create_transaction(&self, publickey: PublicKey, tx_type: TransactionType, fee: Amount, amt: Amount) -> Option<Transaction> {
+        let plan = self.dry_run_transaction(fee, amt)?;
 
-               let mut tx = Transaction::new(tx_type);
-               for from_slip in slips.iter() {
-                   tx.add_from_slip(from_slip.clone());
-               }
+        let mut tx = Transaction::new(tx_type);
+        for from_slip in plan.inputs.into_iter() {
+            tx.add_from_slip(from_slip);
+        }
 
-               tx.add_to_slip(to_slip);
+        if !amt.is_zero() {
+            let mut to_slip = Slip::new(publickey);
+            to_slip.amount = amt;
+            tx.add_to_slip(to_slip);
+        }
 
-               return Some(tx);
-           },
-           None => { return None; },
-       }
-       
+        let mut change_slip = Slip::new(self.publickey);
+        change_slip.amount = plan.change;
+        tx.add_to_slip(change_slip);
 
+        return Some(tx);
+    }
+
+    // the inputs, change and fee of a transaction paying amt with fee,
+    // without creating it or marking anything pending
+    pub fn dry_run_transaction(&self, fee: Amount, amt: Amount) -> Option<SpendPlan> {
+        let total = fee.checked_add(amt)?;
+        let inputs = self.return_available_inputs(total)?;
+        return SpendPlan::new(inputs, amt, fee);
+    }
+
+    pub fn set_coin_selection(&mut self, coin_selection: Box<dyn CoinSelection>) {
+        self.coin_selection = coin_selection;
     }
 
     pub fn create_empty_golden_ticket(&self) -> Transaction {
@@ -667,18 +1146,18 @@ impl Wallet {
         return tx
     }
 
-    pub fn return_available_inputs(&self, amount: f32) -> Option<Vec<Slip>> {
-        let mut slip_vec: Vec<Slip> = Vec::new();
-        let mut slip_sum_amount: f32 = 0.0;
+    pub fn return_available_inputs(&self, amount: Amount) -> Option<Vec<Slip>> {
+        return self.coin_selection.select(&self.return_spendable_inputs(), amount);
+    }
 
-        for slip in self.inputs.values() {
-            slip_sum_amount += slip.amount; 
-            slip_vec.push(slip.clone());
-            if slip_sum_amount > amount {
-                return Some(slip_vec);
-            }
-        }
-        return None;
+    // unspent slips that no pending transaction uses, oldest first
+    pub fn return_spendable_inputs(&self) -> Vec<Slip> {
+        let mut slips: Vec<Slip> = self.inputs.iter()
+            .filter(|(index, _)| !self.spends.contains_key(*index))
+            .map(|(_, slip)| slip.clone())
+            .collect();
+        coinselection::sort_candidates(&mut slips);
+        return slips;
     }
 }
 
@@ -699,9 +1178,50 @@ pub struct Block {
     pub transactions: Vec<Transaction>,
     difficulty: f32,
     paysplit: f32,
-    treasury: f32,
-    coinbase: f32,
-    reclaimed: f32
+    treasury: Amount,
+    coinbase: Amount,
+    reclaimed: Amount,
+    burnfee: Amount,
+    paid_burnfee: Amount
+}
+
+// everything a block commits to, the transactions are committed to through
+// the merkle root so the header is all that needs hashing
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BlockHeader {
+    id: u32,
+
+    #[serde(with = "serde_bytes")]
+    previous_hash: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    merkle_root: Vec<u8>,
+
+    timestamp: u128,
+    creator: PublicKey,
+    difficulty: f32,
+    paysplit: f32,
+    treasury: Amount,
+    coinbase: Amount,
+    reclaimed: Amount,
+    burnfee: Amount,
+    paid_burnfee: Amount
+}
+
+impl BlockHeader {
+    pub fn return_block_hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(self.to_bytes());
+        return hasher.result().to_vec();
+    }
+
+    pub fn return_id(&self) -> u32 {
+        return self.id;
+    }
+
+    pub fn return_previous_hash(&self) -> Vec<u8> {
+        return self.previous_hash.clone();
+    }
 }
 
 impl Block {
@@ -715,32 +1235,58 @@ impl Block {
             transactions: Vec::new(),
             difficulty: 0.0,
             paysplit: 0.5,
-            treasury: 2868100000.0,
-            coinbase: 0.0,
-            reclaimed: 0.0
+            treasury: Amount::from_saito(2868100000),
+            coinbase: Amount::zero(),
+            reclaimed: Amount::zero(),
+            burnfee: BurnFee::new().return_fee(),
+            paid_burnfee: Amount::zero()
         };
     }
 
-    // this needs updating
-    pub fn return_block_hash(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        let id_bytes: [u8; 4] = unsafe { transmute(self.id.to_be()) };
-        let timestamp_bytes: [u8; 16] = unsafe { transmute(self.timestamp.to_be()) };
-        let address_bytes: Vec<u8> = self.creator.serialize().iter().cloned().collect();
-
-        hasher.input(id_bytes);
-        hasher.input(self.previous_hash.as_slice());
-        hasher.input(timestamp_bytes);
-        hasher.input(address_bytes);
+    // a block without transactions, as read back from its header alone
+    pub fn from_header(header: BlockHeader) -> Block {
+        return Block {
+            id: header.id,
+            previous_hash: header.previous_hash,
+            merkle_root: header.merkle_root,
+            timestamp: header.timestamp,
+            creator: header.creator,
+            transactions: Vec::new(),
+            difficulty: header.difficulty,
+            paysplit: header.paysplit,
+            treasury: header.treasury,
+            coinbase: header.coinbase,
+            reclaimed: header.reclaimed,
+            burnfee: header.burnfee,
+            paid_burnfee: header.paid_burnfee
+        };
+    }
 
-        let block_hash = hasher.result();
-        return block_hash.to_vec()
+    pub fn return_block_hash(&self) -> Vec<u8> {
+        return self.return_header().return_block_hash();
     }
 
     pub fn return_id(&self) -> u32 {
         return self.id;
     }
 
+    pub fn return_header(&self) -> BlockHeader {
+        return BlockHeader {
+            id: self.id,
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            timestamp: self.timestamp,
+            creator: self.creator,
+            difficulty: self.difficulty,
+            paysplit: self.paysplit,
+            treasury: self.treasury,
+            coinbase: self.coinbase,
+            reclaimed: self.reclaimed,
+            burnfee: self.burnfee,
+            paid_burnfee: self.paid_burnfee
+        };
+    }
+
     pub fn return_timestamp(&self) -> u128 {
         return self.timestamp;
     }
@@ -755,10 +1301,21 @@ impl Block {
 
     fn validate(&self, previous_block: &Block) -> bool {
         // check that the new block timestamp is greater than the old one
+        if !self.validate_timestamp(previous_block) { return false }
+
+        // check that the block builds on the block it claims to
+        if self.previous_hash != previous_block.return_block_hash() { return false; }
+        if self.id != previous_block.id + 1 { return false; }
+
+        if self.transactions_to_bytes().len() > MAX_BLOCK_SIZE {
+            println!("BLOCK EXCEEDS MAXIMUM SIZE");
+            return false;
+        }
 
-        // we need a way to fetch the current blocks prevhash instead of just getting the last
-        // block in the block chain
-        if previous_block.timestamp >= self.timestamp { return false }
+        if !self.validate_slip_ids() {
+            println!("TRANSACTION OR SLIP IDS DO NOT MATCH BLOCK");
+            return false;
+        }
 
         // ensure no duplicate input slips
         let mut tx_input_hashmap: HashMap<Vec<u8>, u8> = HashMap::new();
@@ -774,43 +1331,213 @@ impl Block {
                 let lower_block_limit: i64 = self.id as i64 - GENESIS_PERIOD as i64;
                 let block_id_64: i64 = slip.block_id as i64;
                 if block_id_64 < lower_block_limit && tx.tx_type == TransactionType::Base {
-                    if slip.amount > 0.0 {
+                    if !slip.amount.is_zero() {
                         return false;
                     }
                     // remove from mempool
                 }
             }
             
-            // validate non-rebroadcast tx
+            // validate non-rebroadcast tx, fees are paid out through the
+            // golden ticket so nobody gets to write their own fee transaction
             match tx.tx_type {
                 TransactionType::Base => { if !tx.validate() { return false; } },
                 TransactionType::GoldenTicket => { if !tx.validate() { return false; } },
+                TransactionType::Fee => {
+                    println!("BLOCK CONTAINS A FEE TRANSACTION");
+                    return false;
+                },
                 _ => {},
             }
 
         }
 
-        // validate merkle root
-        if self.merkle_root != create_merkle_root(&self.transactions) { return false; }
+        // validate merkle root, which is what ties the header to the body
+        if self.merkle_root != create_merkle_root(&self.transactions) {
+            println!("MERKLE ROOT DOES NOT MATCH TRANSACTIONS");
+            return false;
+        }
+
+        // validate monetary policy
+        if !self.validate_monetary_policy(previous_block) {
+            println!("TREASURY OR COINBASE DOES NOT FOLLOW FROM PREVIOUS BLOCK");
+            return false;
+        }
+
+        if !self.verify_signatures() {
+            println!("INVALID TRANSACTION SIGNATURE");
+            return false;
+        }
+
+        if !self.validate_golden_ticket(previous_block) {
+            println!("INVALID GOLDEN TICKET");
+            return false;
+        }
+
+        if (self.difficulty, self.paysplit) != self.return_difficulty_and_paysplit(previous_block) {
+            println!("DIFFICULTY OR PAYSPLIT DOES NOT FOLLOW FROM GOLDEN TICKET");
+            return false;
+        }
 
         return true;
 
-        // validate burn fee and fee transaction
-        // validate golden ticket
-        // validate difficulty
-        // validate monetary policy
+        // validate fee transaction
+
+    }
+
+    // a genesis block starts from the initial state with nothing in it,
+    // only its creator and timestamp are its own
+    fn validate_genesis(&self) -> bool {
+        let mut genesis_block = Block::new(Vec::new(), self.creator);
+        genesis_block.timestamp = self.timestamp;
+        genesis_block.merkle_root = self.merkle_root.clone();
+        return self == &genesis_block;
+    }
+
+    // outputs are keyed by where they were created, so they have to carry
+    // this block and their transaction, and be numbered one after another
+    // the way bundle_transactions numbers them
+    fn validate_slip_ids(&self) -> bool {
+        let block_hash = self.return_block_hash();
+        let mut next_tx_id: Option<u32> = None;
+        let mut next_slip_id: Option<u32> = None;
+        for tx in self.transactions.iter() {
+            if next_tx_id.map_or(false, |next_tx_id| tx.id != next_tx_id) { return false; }
+            next_tx_id = tx.id.checked_add(1);
+            if next_tx_id.is_none() { return false; }
+
+            for slip in tx.to.iter() {
+                if slip.block_hash != block_hash || slip.block_id != self.id || slip.transaction_id != tx.id {
+                    return false;
+                }
+                if next_slip_id.map_or(false, |next_slip_id| slip.id != next_slip_id) { return false; }
+                next_slip_id = slip.id.checked_add(1);
+                if next_slip_id.is_none() { return false; }
+            }
+        }
+        return true;
+    }
+
+    // what can be checked without the transactions, so that a chain of
+    // headers can be verified before any of its bodies are downloaded
+    fn validate_header(&self, previous_block: &Block) -> bool {
+        if !self.validate_timestamp(previous_block) { return false; }
+        if self.previous_hash != previous_block.return_block_hash() { return false; }
+        if self.id != previous_block.id + 1 { return false; }
+
+        let (paid_burnfee, burnfee) = BurnFee::calculate_for_block(previous_block, self.timestamp);
+        if self.paid_burnfee != paid_burnfee || self.burnfee != burnfee { return false; }
+
+        return self.validate_monetary_policy(previous_block);
+    }
+
+    // a block comes after its parent and is not stamped in the future, or a
+    // creator could skip ahead to a block with no burn fee to pay
+    fn validate_timestamp(&self, previous_block: &Block) -> bool {
+        if previous_block.timestamp >= self.timestamp { return false; }
+        return self.timestamp <= time_since_unix_epoch() + MAX_TIMESTAMP_DRIFT;
+    }
+
+    // the burn fee is priced from the previous block and the timestamp, so
+    // every node arrives at the same answer, and has to be covered by the
+    // fees the creator can use
+    fn validate_burnfee(&self, previous_block: &Block) -> bool {
+        if !self.validate_timestamp(previous_block) { return false; }
+
+        let (paid_burnfee, burnfee) = BurnFee::calculate_for_block(previous_block, self.timestamp);
+        if self.paid_burnfee != paid_burnfee || self.burnfee != burnfee { return false; }
+
+        return self.return_available_fees(&self.creator) >= self.paid_burnfee;
+    }
+
+    // treasury and coinbase are fixed by the previous block, see
+    // bundle_with_previous_block
+    fn validate_monetary_policy(&self, previous_block: &Block) -> bool {
+        let treasury = match previous_block.treasury.checked_add(previous_block.reclaimed) {
+            Some(treasury) => treasury,
+            None => return false,
+        };
+        let coinbase = treasury.checked_div(GENESIS_PERIOD as u64).unwrap();
+
+        return self.coinbase == coinbase && Some(self.treasury) == treasury.checked_sub(coinbase);
+    }
+
+    // a block carries at most one golden ticket, which has to solve the
+    // previous block and pay out exactly the shares that block makes available
+    fn validate_golden_ticket(&self, previous_block: &Block) -> bool {
+        let golden_txs: Vec<&Transaction> = self.transactions.iter()
+            .filter(|tx| tx.tx_type == TransactionType::GoldenTicket)
+            .collect();
+
+        if golden_txs.len() > 1 {
+            println!("MORE THAN ONE GOLDEN TICKET IN BLOCK");
+            return false;
+        }
+
+        return match golden_txs.first() {
+            Some(golden_tx) => previous_block.validate_golden_ticket_transaction(golden_tx),
+            None => true,
+        };
+    }
+
+    // a golden ticket has to solve this block and pay out exactly the shares
+    // it makes available, both in a block and on its way into the mempool
+    fn validate_golden_ticket_transaction(&self, golden_tx: &Transaction) -> bool {
+        let golden_ticket = match GoldenTicket::from_bytes(&golden_tx.msg) {
+            Ok(golden_ticket) => golden_ticket,
+            Err(_) => return false,
+        };
 
+        let block_hash = self.return_block_hash();
+        if golden_ticket.target != block_hash { return false; }
+        if !is_valid_solution(&golden_ticket.return_solution(), &block_hash, self.difficulty) {
+            return false;
+        }
+
+        // only the miner who found the solution can claim it
+        if golden_tx.from.iter().any(|slip| slip.address != golden_ticket.publickey) { return false; }
+
+        // the last two outputs are the miner and node shares, anything before
+        // them is change and cannot exceed the inputs
+        let payout_index = match golden_tx.to.len().checked_sub(2) {
+            Some(payout_index) => payout_index,
+            None => return false,
+        };
+        let change = match Amount::checked_sum(golden_tx.to[..payout_index].iter().map(|slip| slip.amount)) {
+            Some(change) => change,
+            None => return false,
+        };
+        if change > golden_tx.calculate_from_amount() { return false; }
+
+        let (miner_share, node_share) = match self.calculate_golden_ticket_shares() {
+            Some(shares) => shares,
+            None => return false,
+        };
+
+        let miner_slip = &golden_tx.to[payout_index];
+        let node_slip = &golden_tx.to[payout_index + 1];
+        return miner_slip.address == golden_ticket.publickey
+            && miner_slip.amount == miner_share
+            && node_slip.address == self.find_winner(&golden_ticket.return_solution())
+            && node_slip.amount == node_share;
+    }
+
+    // signatures are independent of each other so a large block has them
+    // checked in parallel
+    pub fn verify_signatures(&self) -> bool {
+        let secp = Secp256k1::verification_only();
+        return self.transactions.par_iter().all(|tx| tx.verify_signature_with(&secp));
     }
 
     pub fn bundle(&mut self, blocks: &RefMut<Vec<Block>>, transactions: Vec<Transaction>, last_tx_id: u32, last_slip_id: u32) {
         match blocks.last() {
            Some(previous_block) => {
                self.bundle_with_previous_block(previous_block);
-               self.bundle_transactions(transactions, last_tx_id, last_slip_id);
-//               self.calculate_difficulty()
+               self.bundle_transactions(transactions, last_tx_id, last_slip_id, MAX_BLOCK_SIZE);
+               self.calculate_difficulty(previous_block);
            },
            None => {
-               self.bundle_transactions(transactions, last_tx_id, last_slip_id);
+               self.bundle_transactions(transactions, last_tx_id, last_slip_id, MAX_BLOCK_SIZE);
            }
         }
 
@@ -818,25 +1545,45 @@ impl Block {
 
     fn bundle_with_previous_block(&mut self, previous_block: &Block) {
          self.id = previous_block.id + 1;
-         self.treasury = previous_block.treasury + previous_block.reclaimed;
-         self.coinbase = self.treasury / GENESIS_PERIOD as f32; // hard code this
-         self.treasury = self.treasury - self.coinbase;
+         // the coinbase rounds down and the remainder stays in the treasury
+         self.treasury = previous_block.treasury.checked_add(previous_block.reclaimed).unwrap();
+         self.coinbase = self.treasury.checked_div(GENESIS_PERIOD as u64).unwrap(); // hard code this
+         self.treasury = self.treasury.checked_sub(self.coinbase).unwrap();
          self.previous_hash = previous_block.return_block_hash();
          self.paysplit = previous_block.paysplit;
          self.difficulty = previous_block.difficulty;
+
+         let (paid_burnfee, burnfee) = BurnFee::calculate_for_block(previous_block, self.timestamp);
+         self.paid_burnfee = paid_burnfee;
+         self.burnfee = burnfee;
     }
 
-    fn bundle_transactions(&mut self, mut transactions: Vec<Transaction>, last_tx_id: u32, last_slip_id: u32) {
+    // takes transactions in the order given for as long as they fit, and the
+    // first golden ticket only, the ones left out stay in the mempool for
+    // the next block
+    fn bundle_transactions(&mut self, mut transactions: Vec<Transaction>, last_tx_id: u32, last_slip_id: u32, max_size: usize) {
         let mut min_slip_id: u32 = last_slip_id;
         let mut min_tx_id: u32 = last_tx_id;
+        let mut has_golden_ticket = false;
+
+        // the transaction count is part of the block size
+        let mut block_size: usize = 4;
 
         for tx in transactions.iter_mut() {
+            let tx_size = tx.return_size();
+            if block_size + tx_size > max_size { continue; }
+            if tx.tx_type == TransactionType::GoldenTicket {
+                if has_golden_ticket { continue; }
+                has_golden_ticket = true;
+            }
+            block_size += tx_size;
+
             for i in 0..tx.to.len() {
                 tx.to[i].id = min_slip_id;
                 min_slip_id = min_slip_id + 1;
             }
 
-            tx.id = last_tx_id;
+            tx.id = min_tx_id;
             min_tx_id = min_tx_id + 1;
 
             //println!("{:?}", tx);
@@ -855,19 +1602,27 @@ impl Block {
         }
     }
 
-//    fn calculate_difficulty(&mut self) {
-//        for tx in self.transactions.iter() {
-//            if tx.tx_type == TransactionType::GoldenTicket {
-//                match tx.ty_type {
-//                    TransactionType::GoldenTicket => {
-//                        self.difficulty = tx.calculate_difficulty(previous_block);
-//                        self.paysplit = tx.calculate_paysplit(previous_block);
-//                    }
-//                    _ => {}
-//                }
-//            }
-//        }
-//    }
+    fn calculate_difficulty(&mut self, previous_block: &Block) {
+        let (difficulty, paysplit) = self.return_difficulty_and_paysplit(previous_block);
+        self.difficulty = difficulty;
+        self.paysplit = paysplit;
+    }
+
+    // the golden ticket in a block votes on the difficulty and paysplit of
+    // the block it solves, without one they carry over unchanged
+    fn return_difficulty_and_paysplit(&self, previous_block: &Block) -> (f32, f32) {
+        for tx in self.transactions.iter() {
+            if tx.tx_type == TransactionType::GoldenTicket {
+                if let Ok(golden_ticket) = GoldenTicket::from_bytes(&tx.msg) {
+                    return (
+                        golden_ticket.calculate_difficulty(previous_block),
+                        golden_ticket.calculate_paysplit(previous_block)
+                    );
+                }
+            }
+        }
+        return (previous_block.difficulty, previous_block.paysplit);
+    }
 
     pub fn save(&self) {
         let mut filename = "data/".to_string();
@@ -879,14 +1634,85 @@ impl Block {
         f.write_all(&encode[..]);
     }
 
-    fn return_available_fees(&self, key: &PublicKey) -> f32 {
-        let mut total_fees: f32 = 0.0;
+    fn return_available_fees(&self, key: &PublicKey) -> Amount {
+        return Amount::checked_sum(
+            self.transactions.iter().map(|tx| tx.return_fees_usable(key))
+        ).unwrap_or(Amount::zero());
+    }
+
+    fn return_total_fees(&self) -> Amount {
+        return Amount::checked_sum(
+            self.transactions.iter().map(|tx| tx.return_fees())
+        ).unwrap_or(Amount::zero());
+    }
+
+    // what the golden ticket solving this block pays its miner and the
+    // winning node, the creator keeps whatever fees they collected above
+    // the burn fee they paid and the rest is split with the coinbase. The
+    // miner share rounds down and the node gets the remainder
+    pub fn calculate_golden_ticket_shares(&self) -> Option<(Amount, Amount)> {
+        let creator_surplus = self.return_available_fees(&self.creator).saturating_sub(self.paid_burnfee);
+        let total_fees_for_miners_and_nodes = self.return_total_fees()
+            .saturating_sub(creator_surplus)
+            .checked_add(self.coinbase)?;
+
+        let paysplit_basis_points = (self.paysplit * PAYSPLIT_BASIS_POINTS as f32).round() as u64;
+        let miner_share = total_fees_for_miners_and_nodes
+            .checked_mul_ratio(paysplit_basis_points, PAYSPLIT_BASIS_POINTS)?;
+        let node_share = total_fees_for_miners_and_nodes.checked_sub(miner_share)?;
+
+        return Some((miner_share, node_share));
+    }
+
+    // the node paid by the golden ticket that solves this block
+    //
+    // the solution picks a transaction with odds proportional to the
+    // routing work it carries, and then a node along its routing path, each
+    // hop half as likely as the one before it just as its routing work is
+    // halved. golden tickets carry no routing work, and without any the
+    // creator wins
+    pub fn find_winner(&self, random_solution: &[u8]) -> PublicKey {
+        let routing_work: Vec<(&Transaction, u128)> = self.transactions.iter()
+            .filter(|tx| tx.tx_type != TransactionType::GoldenTicket)
+            .map(|tx| (tx, tx.return_routing_work().return_nolan() as u128))
+            .filter(|(_, work)| *work > 0)
+            .collect();
+        let total_work: u128 = routing_work.iter().map(|(_, work)| work).sum();
+        if total_work == 0 { return self.creator; }
+
+        let mut winning_point = return_random_fraction(random_solution, total_work);
+        let mut winning_tx = routing_work[routing_work.len() - 1].0;
+        for (tx, work) in routing_work.iter() {
+            if winning_point < *work {
+                winning_tx = tx;
+                break;
+            }
+            winning_point -= work;
+        }
 
-        for tx in self.transactions.iter() {
-            total_fees += tx.return_fees_usable(key);
+        // the hop is picked with a second number drawn from the first
+        let mut hasher = Sha256::new();
+        hasher.input(random_solution);
+        let hop_solution = hasher.result().to_vec();
+
+        let hops = &winning_tx.path[..winning_tx.path.len().min(64)];
+        if hops.is_empty() { return self.creator; }
+
+        let hop_weights: Vec<u128> = (0..hops.len()).map(|i| 1u128 << (hops.len() - 1 - i)).collect();
+        let mut winning_point = return_random_fraction(&hop_solution, hop_weights.iter().sum());
+        for (hop, weight) in hops.iter().zip(hop_weights.iter()) {
+            if winning_point < *weight {
+                return hop.to;
+            }
+            winning_point -= weight;
         }
+        return hops[hops.len() - 1].to;
+    }
 
-        return total_fees;
+    // the fees burned by the creator to produce this block, which is what
+    // fork choice weighs chains by
+    pub fn return_work(&self) -> Amount {
+        return self.return_available_fees(&self.creator);
     }
 
     pub fn return_slip_len(&self) -> u32 {
@@ -903,126 +1729,426 @@ impl Block {
     }
 }
 
+// entry in the block tree, one for every block we have seen whether or
+// not it sits on the longest chain
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    pub id: u32,
+    pub hash: Vec<u8>,
+    pub previous_hash: Vec<u8>,
+    pub burnfee: Amount,
+    pub lc: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AddBlockResult {
+    // block extended the longest chain
+    Extended,
+    // block made a fork heavier than the longest chain, carries the blocks
+    // that were unwound off the old chain
+    Reorganized(Vec<Block>),
+    // block was stored on a fork lighter than the longest chain
+    Fork,
+    // we do not have the parent of this block
+    Orphan,
+    Duplicate,
+    Invalid,
+    // block could not be written to disk
+    NotSaved,
+}
+
 #[derive(Debug)]
 pub struct Blockchain {
     genesis_ts: u128,
     last_block_id: u32,
     last_tx_id: u32,
     last_slip_id: u32,
+
+    // hashes of the longest chain, genesis first, and the bodies of its
+    // latest blocks with the tip last
+    longest_chain: Vec<Vec<u8>>,
     pub blocks: RefCell<Vec<Block>>,
     pub shashmap: HashMap<Vec<u8>, u32>,
 
+    // every block we know about keyed by block hash, the bodies of blocks
+    // off the longest chain are only read from the store when needed
+    pub index: HashMap<Vec<u8>, BlockIndex>,
+    // blocks that failed to wind and everything built on them, so they are
+    // never tried again
+    invalid: HashSet<Vec<u8>>,
+    store: BlockStore,
 }
 
 impl Blockchain {
     pub fn new () -> Blockchain {
+        return Blockchain::new_with_directory(Path::new("data"));
+    }
+
+    pub fn new_with_directory(directory: &Path) -> Blockchain {
         return Blockchain {
             genesis_ts: time_since_unix_epoch(),
             last_block_id: 0,
             last_tx_id: 1,
             last_slip_id: 1,
+            longest_chain: Vec::new(),
             blocks: RefCell::new(Vec::new()),
             shashmap: HashMap::new(), 
+            index: HashMap::new(),
+            invalid: HashSet::new(),
+            store: BlockStore::new(directory),
         };
     }
 
-    pub fn initialize(&mut self, wallet: &mut Wallet) {
-        self.blocks = RefCell::new(self.load_blocks_from_disk());
-        
-        for block in self.blocks.borrow().iter() {
-            wallet.process_payment(&block.transactions);
-            //self.update_shashmap(block)
+    // a store that can not be recovered is an error rather than an empty
+    // chain, so we never write a new genesis block over an existing one
+    pub fn initialize(&mut self, wallet: &mut Wallet) -> std::io::Result<()> {
+        let report = self.store.recover()?;
+
+        if report.truncated_index {
+            println!("BLOCK INDEX WAS TRUNCATED");
+        }
+        for path in report.quarantined.iter() {
+            println!("QUARANTINED CORRUPT BLOCK FILE -- {:?}", path);
+        }
+        for block_hash in report.dropped.iter() {
+            println!("DROPPED BLOCK -- {}", block_hash.to_base58());
+        }
+        for block_hash in report.reindexed.iter() {
+            println!("REINDEXED BLOCK -- {}", block_hash.to_base58());
+        }
+        for path in report.legacy.iter() {
+            println!("IGNORING BLOCK FILE FROM AN OLDER VERSION -- {:?}", path);
         }
 
-        match self.blocks.borrow().last() {
-            Some(last_block) => {
-                self.last_block_id = last_block.return_id();
-                self.last_tx_id = last_block.return_tx_len() + self.return_last_tx_id();
-                self.last_slip_id = last_block.return_slip_len() + self.return_last_slip_id();
-            },
-            None => {},
+        // only block headers are read here, the bodies of blocks on the
+        // longest chain are loaded as they are wound and only the latest
+        // stay in memory
+        let entries = report.entries;
+        for entry in entries.iter() {
+            let header = match self.store.read_header(&entry.hash) {
+                Ok(header) => header,
+                Err(err) => {
+                    println!("FAILED TO READ BLOCK HEADER -- {}", err);
+                    continue;
+                }
+            };
+            let block_index = BlockIndex {
+                id: entry.id,
+                hash: entry.hash.clone(),
+                previous_hash: header.previous_hash.clone(),
+                burnfee: entry.work,
+                lc: false,
+            };
+            self.connect_block(block_index, None, wallet);
+        }
+        println!("READ {} BLOCKS FROM INDEX -- {}", entries.len(), time_since_unix_epoch());
+        return Ok(());
+    }
+
+    pub fn add_block(&mut self, new_block: Block, wallet: &mut Wallet) -> AddBlockResult {
+        if self.index.contains_key(&new_block.return_block_hash()) {
+            return AddBlockResult::Duplicate;
+        }
+
+        if self.invalid.contains(&new_block.return_block_hash()) || self.invalid.contains(&new_block.previous_hash) {
+            self.invalid.insert(new_block.return_block_hash());
+            return AddBlockResult::Invalid;
+        }
+
+        // a block is checked, and only then written to disk, once its parent
+        // is on the chain, until then it is held in memory
+        let starts_chain = new_block.previous_hash.is_empty() && self.index.is_empty();
+        if !starts_chain && !self.index.contains_key(&new_block.previous_hash) {
+            println!("PARENT BLOCK NOT FOUND");
+            return AddBlockResult::Orphan;
+        }
+
+        if !self.validate_block(&new_block) {
+            println!("BLOCK INVALID");
+            return AddBlockResult::Invalid;
+        }
+
+        if let Err(err) = self.save_block(&new_block) {
+            println!("FAILED TO WRITE BLOCK TO DISK -- {}", err);
+            return AddBlockResult::NotSaved;
         }
+
+        let block_index = BlockIndex {
+            id: new_block.id,
+            hash: new_block.return_block_hash(),
+            previous_hash: new_block.previous_hash.clone(),
+            burnfee: new_block.return_work(),
+            lc: false,
+        };
+        return self.connect_block(block_index, Some(new_block), wallet);
     }
+    
+    pub fn save_block(&mut self, block: &Block) -> std::io::Result<()> {
+        if self.store.contains(&block.return_block_hash()) { return Ok(()); }
+        self.store.write_block(block)?;
+        return Ok(());
+    }
+
+    // insert the block into the block tree and run fork choice, the body
+    // is read from the store if it is not passed in
+    fn connect_block(&mut self, mut block_index: BlockIndex, new_block: Option<Block>, wallet: &mut Wallet) -> AddBlockResult {
+        let block_hash = block_index.hash.clone();
+        if self.index.contains_key(&block_hash) {
+            return AddBlockResult::Duplicate;
+        }
 
-    fn load_blocks_from_disk(&mut self) -> Vec<Block> {
-        let mut blocks: Vec<Block> = Vec::new();
-        let dir = Path::new("data");
-        if dir.is_dir() {
-            for entry in read_dir(dir).unwrap() {
-                let entry = entry.unwrap();
-                let path = entry.path();
+        match self.index.get(&block_index.previous_hash) {
+            Some(previous_index) => {
+                block_index.burnfee = match block_index.burnfee.checked_add(previous_index.burnfee) {
+                    Some(burnfee) => burnfee,
+                    None => return AddBlockResult::Invalid,
+                };
+            },
+            None => {
+                // only a genesis block starts the chain
+                if !self.index.is_empty() || !block_index.previous_hash.is_empty() {
+                    println!("PARENT BLOCK NOT FOUND");
+                    return AddBlockResult::Orphan;
+                }
+            }
+        };
 
-                println!("{:?}", path);
+        let extends_longest_chain = block_index.previous_hash == self.return_latest_hash();
+        let is_heavier = match self.index.get(&self.return_latest_hash()) {
+            Some(latest_index) => Blockchain::is_heavier(&block_index, latest_index),
+            None => true,
+        };
+
+        self.index.insert(block_hash.clone(), block_index);
 
-                let mut encoded = Vec::<u8>::new();
-                let mut r = File::open(&path).unwrap();
-                r.read_to_end(&mut encoded).unwrap();
+        if !is_heavier {
+            return AddBlockResult::Fork;
+        }
 
-                blocks.push(bincode::deserialize(&encoded[..]).unwrap());
-                println!("READ BLOCK INTO MEMORY -- {}", time_since_unix_epoch());
+        if extends_longest_chain {
+            let new_block = match new_block {
+                Some(new_block) => Some(new_block),
+                None => self.load_block(&block_hash),
+            };
+            if new_block.is_none() || !self.wind_block(new_block.unwrap(), wallet) {
+                self.remove_invalid_blocks(&block_hash);
+                return AddBlockResult::Invalid;
             }
+            return AddBlockResult::Extended;
         }
-        
-        blocks.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
 
-        return blocks;
+        return self.reorganize(&block_hash, new_block, wallet);
     }
 
-    pub fn add_block(&mut self, new_block: Block) {
-        self.save_block(new_block.clone());
-        self.blocks.borrow_mut().push(new_block);
+    // fork choice: the chain that has burned the most fees wins, ties go
+    // to the longer chain and then to the lower block hash so that every
+    // node settles on the same tip
+    fn is_heavier(a: &BlockIndex, b: &BlockIndex) -> bool {
+        if a.burnfee != b.burnfee { return a.burnfee > b.burnfee; }
+        if a.id != b.id { return a.id > b.id; }
+        return a.hash < b.hash;
     }
-    
-    pub fn save_block(&mut self, block: Block) {
-        // update slips
-        //for tx in block.transactions.iter() {
-        //    for slip in tx.to.iter() {
-        //        self.insert_slip(slip.return_index(), block.id);
-        //    }
-        //}
-
-        self.write_block_to_disk(&block);
+
+    fn reorganize(&mut self, new_latest_hash: &Vec<u8>, mut new_block: Option<Block>, wallet: &mut Wallet) -> AddBlockResult {
+        // walk back from the new tip until we hit the longest chain
+        let mut new_chain: Vec<Vec<u8>> = Vec::new();
+        let mut shared_ancestor_hash = new_latest_hash.clone();
+        while let Some(block_index) = self.index.get(&shared_ancestor_hash) {
+            if block_index.lc { break; }
+            new_chain.push(shared_ancestor_hash.clone());
+            shared_ancestor_hash = block_index.previous_hash.clone();
+        }
+        new_chain.reverse();
+
+        let mut unwound_blocks: Vec<Block> = Vec::new();
+        while self.return_latest_hash() != shared_ancestor_hash {
+            let block = self.unwind_block(wallet);
+            unwound_blocks.push(block);
+        }
+
+        let mut wound_count = 0;
+        for block_hash in new_chain.iter() {
+            let block = match new_block.take() {
+                Some(block) if &block.return_block_hash() == block_hash => Some(block),
+                other => {
+                    new_block = other;
+                    self.load_block(block_hash)
+                }
+            };
+            if block.is_none() || !self.wind_block(block.unwrap(), wallet) {
+                self.remove_invalid_blocks(block_hash);
+
+                // what was wound of the fork is valid, keep it if it
+                // outweighs the chain it replaced
+                let previous_latest_hash = match unwound_blocks.first() {
+                    Some(previous_latest_block) => previous_latest_block.return_block_hash(),
+                    None => shared_ancestor_hash.clone(),
+                };
+                if Blockchain::is_heavier(&self.index[&self.return_latest_hash()], &self.index[&previous_latest_hash]) {
+                    println!("REORGANIZATION STOPPED AT INVALID BLOCK -- {} BLOCKS UNWOUND", unwound_blocks.len());
+                    return AddBlockResult::Reorganized(unwound_blocks);
+                }
+
+                println!("REORGANIZATION FAILED, RESTORING PREVIOUS CHAIN");
+                for _ in 0..wound_count {
+                    self.unwind_block(wallet);
+                }
+
+                while let Some(block) = unwound_blocks.pop() {
+                    self.wind_block(block, wallet);
+                }
+
+                return AddBlockResult::Invalid;
+            }
+            wound_count += 1;
+        }
+
+        println!("CHAIN REORGANIZED -- {} BLOCKS UNWOUND", unwound_blocks.len());
+
+        return AddBlockResult::Reorganized(unwound_blocks);
+    }
+
+    // apply a block on top of the longest chain
+    fn wind_block(&mut self, block: Block, wallet: &mut Wallet) -> bool {
+        if !self.validate_transaction_inputs(&block) {
+            println!("TRANSACTION INPUTS INVALID");
+            return false;
+        }
+
         self.update_shashmap(&block);
+        wallet.process_payment(&block.transactions);
+
+        self.last_block_id = block.id;
+        self.last_tx_id = self.last_tx_id + block.return_tx_len();
+        self.last_slip_id = self.last_slip_id + block.return_slip_len();
+
+        self.index.get_mut(&block.return_block_hash()).unwrap().lc = true;
+        self.longest_chain.push(block.return_block_hash());
+
+        let mut blocks = self.blocks.borrow_mut();
+        blocks.push(block);
+        if blocks.len() > MAX_BLOCKS_IN_MEMORY {
+            blocks.remove(0);
+        }
+
+        return true;
     }
 
-    pub fn update_shashmap(&mut self, block: &Block) {
+    // remove the latest block from the longest chain and undo its effects
+    fn unwind_block(&mut self, wallet: &mut Wallet) -> Block {
+        let block_hash = self.longest_chain.pop().unwrap();
+        let block = match self.blocks.borrow_mut().pop() {
+            Some(block) => block,
+            None => self.load_block(&block_hash).expect("block on the longest chain is missing from the store"),
+        };
+
+        // keep the tip in memory when a reorganization runs past the blocks
+        // we hold
+        if self.blocks.borrow().is_empty() {
+            if let Some(tip) = self.longest_chain.last().and_then(|tip_hash| self.load_block(tip_hash)) {
+                self.blocks.borrow_mut().push(tip);
+            }
+        }
+
         for tx in block.transactions.iter() {
             for slip in tx.to.iter() {
-                self.insert_slip(slip.return_index(), block.id);
+                self.shashmap.remove(&slip.return_index());
+            }
+            for slip in tx.from.iter() {
+                if self.shashmap.get(&slip.return_index()) == Some(&0) {
+                    self.insert_slip(slip.return_index(), slip.block_id);
+                }
             }
         }
+
+        wallet.rollback_payment(&block.transactions);
+
+        self.last_block_id = match self.longest_chain.last() {
+            Some(previous_hash) => self.index[previous_hash].id,
+            None => 0,
+        };
+        self.last_tx_id = self.last_tx_id - block.return_tx_len();
+        self.last_slip_id = self.last_slip_id - block.return_slip_len();
+
+        self.index.get_mut(&block_hash).unwrap().lc = false;
+
+        return block;
     }
 
-    fn write_block_to_disk(&self, block: &Block) {
-        let mut filename = "data/".to_string();
-        filename.push_str(&time_since_unix_epoch().to_string());
-        filename.push_str(&".sai".to_string());
+    // drop a block that failed to wind along with everything built on it
+    fn remove_invalid_blocks(&mut self, block_hash: &Vec<u8>) {
+        let mut invalid_hashes: Vec<Vec<u8>> = vec![block_hash.clone()];
+        while let Some(invalid_hash) = invalid_hashes.pop() {
+            self.index.remove(&invalid_hash);
+            self.invalid.insert(invalid_hash.clone());
 
-        let encode: Vec<u8> = bincode::serialize(block).unwrap();
-        let mut f = File::create(filename).unwrap();
-        f.write_all(&encode[..]);
+            for (hash, block_index) in self.index.iter() {
+                if block_index.previous_hash == invalid_hash {
+                    invalid_hashes.push(hash.clone());
+                }
+            }
+        }
+    }
+
+    pub fn update_shashmap(&mut self, block: &Block) {
+        for tx in block.transactions.iter() {
+            for slip in tx.from.iter() {
+                if self.shashmap.contains_key(&slip.return_index()) {
+                    self.insert_slip(slip.return_index(), 0);
+                }
+            }
+            for slip in tx.to.iter() {
+                self.insert_slip(slip.return_index(), block.id);
+            }
+        }
     }
 
     pub fn validate_block(&self, new_block: &Block) -> bool {
-        if new_block.return_id() > 2 {
-            // validate inputs internally 
-            if !new_block.validate(self.blocks.borrow_mut().last().unwrap()) { 
-                println!("BLOCK FAILED TO VALIDATE");
-                return false; 
+        // the genesis block has nothing to be checked against but has to
+        // start from the initial state, and there is only ever one
+        if new_block.previous_hash.is_empty() {
+            if !self.index.is_empty() || !new_block.validate_genesis() {
+                println!("INVALID GENESIS BLOCK");
+                return false;
             }
+            return true;
+        }
 
-            // validate the inputs
+        let previous_block = match self.get_block_by_hash(&new_block.previous_hash) {
+            Some(previous_block) => previous_block,
+            None => {
+                println!("PARENT BLOCK NOT FOUND");
+                return false;
+            }
+        };
+
+        if !new_block.validate_burnfee(&previous_block) {
+            println!("BLOCK DOES NOT PAY THE BURN FEE");
+            return false;
+        }
+
+        // validate inputs internally 
+        if !new_block.validate(&previous_block) { 
+            println!("BLOCK FAILED TO VALIDATE");
+            return false; 
+        }
+
+        // inputs of blocks on a fork are checked when the fork is wound
+        // onto the longest chain
+        if new_block.previous_hash == self.return_latest_hash() {
             if !self.validate_transaction_inputs(&new_block) {
                 println!("TRANSACTION INPUTS INVALID");
                 return false;
             }
-        } 
+        }
         return true;
     }
 
     fn validate_transaction_inputs(&self, block: &Block) -> bool {
         for tx in block.transactions.iter() {
             for slip in tx.from.iter() {
+                // empty slips such as those on golden tickets spend nothing
+                if slip.amount.is_zero() { continue; }
                 if !self.validate_existing_slip(&slip.return_index(), &block.id) {
                     return false;
                 };
@@ -1037,6 +2163,11 @@ impl Blockchain {
         self.shashmap.insert(slip_index, current_block_id);
     }
 
+    // unspent on the longest chain
+    pub fn is_slip_spendable(&self, slip: &Slip) -> bool {
+        return self.validate_existing_slip(&slip.return_index(), &self.last_block_id);
+    }
+
     fn validate_existing_slip(&self, slip_index: &Vec<u8>, current_block_id: &u32) -> bool {
         if !self.shashmap.contains_key(slip_index) { return false; }
         let id = self.shashmap[slip_index];
@@ -1045,8 +2176,72 @@ impl Blockchain {
         return true;
     }
 
+    pub fn get_block_by_hash(&self, block_hash: &Vec<u8>) -> Option<Block> {
+        let block_index = match self.index.get(block_hash) {
+            Some(block_index) => block_index,
+            None => return None,
+        };
+
+        // the latest blocks on the longest chain are already in memory
+        if block_index.lc {
+            for block in self.blocks.borrow().iter().rev() {
+                if block.id == block_index.id {
+                    return Some(block.clone());
+                }
+            }
+        }
+
+        return self.load_block(block_hash);
+    }
+
+    fn load_block(&self, block_hash: &Vec<u8>) -> Option<Block> {
+        return match self.store.read_block(block_hash) {
+            Ok(block) => Some(block),
+            Err(err) => {
+                println!("FAILED TO READ BLOCK FROM DISK -- {}", err);
+                None
+            }
+        };
+    }
+
+    // returns the block with this id on the longest chain
+    pub fn get_block_by_id(&self, id: u32) -> Option<Block> {
+        for block_hash in self.store.return_hashes_by_id(id).iter() {
+            match self.index.get(block_hash) {
+                Some(block_index) if block_index.lc => {
+                    return self.get_block_by_hash(block_hash);
+                },
+                _ => {},
+            }
+        }
+        return None;
+    }
+
+    // a block without its transactions, read from the store
+    pub fn get_header_by_hash(&self, block_hash: &Vec<u8>) -> Option<Block> {
+        if !self.index.contains_key(block_hash) { return None; }
+        return match self.store.read_header(block_hash) {
+            Ok(header) => Some(header),
+            Err(err) => {
+                println!("FAILED TO READ BLOCK HEADER -- {}", err);
+                None
+            }
+        };
+    }
+
+    pub fn return_longest_chain(&self) -> &Vec<Vec<u8>> {
+        return &self.longest_chain;
+    }
+
+    pub fn return_latest_hash(&self) -> Vec<u8> {
+        return match self.longest_chain.last() {
+            Some(latest_hash) => latest_hash.clone(),
+            None => Vec::new(),
+        };
+    }
+
     pub fn return_previous_hash(&self) -> Vec<u8> {
-        return self.blocks.borrow_mut().last().unwrap().return_block_hash();
+        return self.longest_chain.last().unwrap().clone();
     }
 
     pub fn return_last_block_id(&self) -> u32 {
@@ -1062,7 +2257,7 @@ impl Blockchain {
     }
 
     pub fn return_blocks_length(&self) -> usize {
-        return self.blocks.borrow_mut().len();
+        return self.longest_chain.len();
     }
 
     pub fn increment_block_id(&mut self) {
@@ -1080,7 +2275,7 @@ impl Blockchain {
 
 #[derive(Debug)]
 pub struct BurnFee {
-    fee: f32,
+    fee: Amount,
     heartbeat: u32,
     last_block_timestamp: u128,
     last_block_delta: u128
@@ -1089,31 +2284,49 @@ pub struct BurnFee {
 impl BurnFee {
     pub fn new() -> BurnFee {
         return BurnFee {
-            fee: 10.0,
+            fee: Amount::from_saito(10),
             heartbeat: 10,
             last_block_timestamp: time_since_unix_epoch(),
             last_block_delta: 0
         };
     }
 
-    pub fn calculate(&self, mut elapsed_time: u128) -> f32 {
+    // fee / (elapsed_time in seconds), rounded down
+    pub fn calculate(&self, mut elapsed_time: u128) -> Amount {
         //let mut elapsed_time = time_since_unix_epoch()  - self.last_block_timestamp;
 
-        // return 0.0 if it's been twice as long as 10s
-        if (elapsed_time / 1000) > (self.heartbeat as u128 * 2) { return 0.0; }
+        // return 0 if it's been twice as long as 10s
+        if (elapsed_time / 1000) > (self.heartbeat as u128 * 2) { return Amount::zero(); }
 
         if elapsed_time == 0 { elapsed_time = 1; }
 
-        let elapsed_time_float = elapsed_time as f32;
-        return self.fee / (elapsed_time_float / 1000.0);
+        return self.fee.checked_mul_ratio(1000, elapsed_time as u64).unwrap_or(Amount::zero());
     }
 
-    pub fn return_current_burnfee(&self) -> f32 {
-        return self.calculate(time_since_unix_epoch() - self.last_block_timestamp);
+    // the burn fee state a block leaves behind, which prices the block
+    // that follows it
+    pub fn from_block(block: &Block) -> BurnFee {
+        let mut burnfee = BurnFee::new();
+        burnfee.fee = block.burnfee;
+        burnfee.last_block_timestamp = block.timestamp;
+        return burnfee;
     }
 
-    fn return_previous_burnfee(&self) -> f32 {
-        return self.calculate(self.last_block_delta);
+    // the burn fee a block made at timestamp on top of previous_block has to
+    // pay, and the fee it leaves for the block after it
+    pub fn calculate_for_block(previous_block: &Block, timestamp: u128) -> (Amount, Amount) {
+        let mut burnfee = BurnFee::from_block(previous_block);
+        let paid_burnfee = burnfee.calculate(timestamp.saturating_sub(burnfee.last_block_timestamp));
+        burnfee.adjust(timestamp);
+        return (paid_burnfee, burnfee.return_fee());
+    }
+
+    pub fn return_fee(&self) -> Amount {
+        return self.fee;
+    }
+
+    pub fn return_current_burnfee(&self) -> Amount {
+        return self.calculate(time_since_unix_epoch() - self.last_block_timestamp);
     }
 
     pub fn set_timestamp(&mut self, new_block_timestamp: u128) {
@@ -1125,10 +2338,10 @@ impl BurnFee {
     }
 
     pub fn adjust(&mut self, current_block_timestamp: u128) {
-        let numerator = (self.heartbeat as f32 * 10000000.0).sqrt();
-        let denominator = current_block_timestamp as u32 - self.last_block_timestamp as u32 + 1;
+        let numerator = integer_sqrt(self.heartbeat as u64 * 10000000);
+        let denominator = current_block_timestamp.saturating_sub(self.last_block_timestamp) as u64 + 1;
 
-        self.fee = self.fee * (numerator / denominator as f32);
+        self.fee = self.fee.checked_mul_ratio(numerator, denominator).unwrap_or(self.fee);
     }
 }
 
@@ -1214,4 +2427,833 @@ mod tests {
     fn saito_testing() {
         assert_eq!(1, 1);
     }
+
+    #[test]
+    fn solution_difficulty_is_counted_in_fractions_of_a_bit() {
+        let target = vec![0; 32];
+        let mut random_solution = vec![0; 32];
+        random_solution[1] = 0x80;
+
+        assert!(is_valid_solution(&random_solution, &target, 0.0));
+        assert!(is_valid_solution(&random_solution, &target, 1.0));
+        assert!(!is_valid_solution(&random_solution, &target, 1.125));
+
+        // half a bit past the first byte lets through 2^-0.5 of the range
+        // that follows it, everything below 0xb504f333
+        assert!(is_valid_solution(&random_solution, &target, 1.0625));
+        random_solution[1] = 0xc0;
+        assert!(!is_valid_solution(&random_solution, &target, 1.0625));
+        assert!(is_valid_solution(&random_solution, &target, 1.01));
+        random_solution[1..5].copy_from_slice(&[0xb5, 0x04, 0xf3, 0x32]);
+        assert!(is_valid_solution(&random_solution, &target, 1.0625));
+        random_solution[1..5].copy_from_slice(&[0xb5, 0x04, 0xf3, 0x33]);
+        assert!(!is_valid_solution(&random_solution, &target, 1.0625));
+
+        // and a quarter of a bit 2^-0.25 of it, below 0xd744fcca, where a
+        // straight line from all of it to half would allow up to 0xe0000000
+        random_solution[1..5].copy_from_slice(&[0xd7, 0x44, 0xfc, 0xc9]);
+        assert!(is_valid_solution(&random_solution, &target, 1.03125));
+        random_solution[1..5].copy_from_slice(&[0xd7, 0x44, 0xfc, 0xca]);
+        assert!(!is_valid_solution(&random_solution, &target, 1.03125));
+
+        assert!(is_valid_solution(&target, &target, 32.0));
+        assert!(!is_valid_solution(&target, &target, 32.01));
+        assert!(!is_valid_solution(&target, &target, std::f32::NAN));
+    }
+
+    #[test]
+    fn transaction_signature_verifies_against_input_slips() {
+        let wallet = Wallet::new();
+
+        let mut tx = Transaction::new(TransactionType::Base);
+        tx.add_from_slip(Slip::new(wallet.return_publickey()));
+        tx.add_to_slip(Slip::new(wallet.return_publickey()));
+        tx.sig = wallet.create_signature(tx.return_signature_source().as_slice());
+        assert!(tx.verify_signature());
+
+        // bundling into a block does not break the signature
+        tx.to[0].id = 42;
+        assert!(tx.verify_signature());
+
+        tx.to[0].amount = Amount::from_nolan(1);
+        assert!(!tx.verify_signature());
+
+        let (_, other_publickey) = generate_keys();
+        let mut forged_tx = tx.clone();
+        forged_tx.to[0].amount = Amount::zero();
+        forged_tx.add_from_slip(Slip::new(other_publickey));
+        assert!(!forged_tx.verify_signature());
+    }
+
+    fn create_fee_transaction(wallet: &Wallet, fee: u64, msg_len: usize) -> Transaction {
+        let mut tx = Transaction::new(TransactionType::Base);
+        let mut slip = Slip::new(wallet.return_publickey());
+        slip.amount = Amount::from_nolan(1000 + fee);
+        tx.add_from_slip(slip);
+        let mut slip = Slip::new(wallet.return_publickey());
+        slip.amount = Amount::from_nolan(1000);
+        tx.add_to_slip(slip);
+        tx.msg = vec![0; msg_len];
+        tx.sig = wallet.create_signature(tx.return_signature_source().as_slice());
+        return tx;
+    }
+
+    #[test]
+    fn wallet_tracks_pending_spent_and_rolled_back_slips() {
+        let mut wallet = Wallet::new();
+        let mut input = Slip::new(wallet.return_publickey());
+        input.amount = Amount::from_nolan(1000);
+        input.id = 1;
+        let mut other_input = Slip::new(wallet.return_publickey());
+        other_input.amount = Amount::from_nolan(500);
+        other_input.id = 2;
+        wallet.add_input(input.clone());
+        wallet.add_input(other_input.clone());
+
+        let mut tx = Transaction::new(TransactionType::Base);
+        tx.add_from_slip(input.clone());
+        let mut change = Slip::new(wallet.return_publickey());
+        change.amount = Amount::from_nolan(900);
+        change.id = 3;
+        tx.add_to_slip(change.clone());
+        tx.sig = wallet.create_signature(tx.return_signature_source().as_slice());
+
+        // the pending input can not be picked again
+        wallet.mark_pending(&tx);
+        assert_eq!(wallet.return_slip_state(&input), Some(SlipState::Pending));
+        assert_eq!(wallet.return_balance(), Amount::from_nolan(1500));
+        assert_eq!(wallet.return_available_balance(), Amount::from_nolan(500));
+        assert_eq!(wallet.return_pending_balance(), Amount::from_nolan(1400));
+        assert_eq!(wallet.return_available_inputs(Amount::from_nolan(600)), None);
+        assert_eq!(wallet.return_available_inputs(Amount::from_nolan(500)), Some(vec![other_input.clone()]));
+
+        // the change is spent again in the same block
+        let mut spend_change = Transaction::new(TransactionType::Base);
+        spend_change.add_from_slip(change.clone());
+        let block_transactions = vec![tx.clone(), spend_change];
+
+        wallet.process_payment(&block_transactions);
+        assert_eq!(wallet.return_slip_state(&input), Some(SlipState::Spent));
+        assert_eq!(wallet.return_slip_state(&change), Some(SlipState::Spent));
+        assert_eq!(wallet.return_balance(), Amount::from_nolan(500));
+        assert_eq!(wallet.return_pending_balance(), Amount::from_nolan(500));
+
+        wallet.rollback_payment(&block_transactions);
+        assert_eq!(wallet.return_slip_state(&input), Some(SlipState::Unspent));
+        assert_eq!(wallet.return_slip_state(&change), None);
+        assert_eq!(wallet.return_balance(), Amount::from_nolan(1500));
+
+        // a mempool that no longer holds the transaction releases its inputs
+        wallet.mark_pending(&tx);
+        wallet.sync_pending(Vec::<Transaction>::new().iter());
+        assert_eq!(wallet.return_available_balance(), Amount::from_nolan(1500));
+    }
+
+    #[test]
+    fn routing_path_is_signed_hop_by_hop_and_halves_routing_work() {
+        let sender = Wallet::new();
+        let relay = Wallet::new();
+        let producer = Wallet::new();
+        let mut tx = create_fee_transaction(&sender, 1000, 0);
+        assert_eq!(tx.return_routing_work_for(&sender.return_publickey()), Amount::from_nolan(1000));
+
+        // only whoever holds the transaction can pass it on
+        assert!(!tx.add_hop(&relay, producer.return_publickey()));
+        assert!(tx.add_hop(&sender, relay.return_publickey()));
+        assert_eq!(tx.return_routing_work_for(&relay.return_publickey()), Amount::from_nolan(1000));
+        assert!(tx.add_hop(&relay, producer.return_publickey()));
+
+        assert!(tx.verify_signature());
+        assert_eq!(tx.return_routing_work(), Amount::from_nolan(500));
+        assert_eq!(tx.return_routing_work_for(&producer.return_publickey()), Amount::from_nolan(500));
+        assert_eq!(tx.return_routing_work_for(&relay.return_publickey()), Amount::zero());
+        assert_eq!(Transaction::from_bytes(&tx.to_bytes()).unwrap(), tx);
+
+        // a hop that does not follow on from the last one breaks the path
+        let mut broken_tx = tx.clone();
+        broken_tx.path[1].from = sender.return_publickey();
+        assert!(!broken_tx.verify_signature());
+
+        // and so does a path lifted onto another transaction
+        let mut other_tx = create_fee_transaction(&sender, 10, 0);
+        other_tx.path = tx.path.clone();
+        assert!(!other_tx.verify_path());
+    }
+
+    #[test]
+    fn golden_ticket_winner_is_drawn_by_routing_work() {
+        let sender = Wallet::new();
+        let relay = Wallet::new();
+        let producer = Wallet::new();
+        let mut block = Block::new(Vec::new(), producer.return_publickey());
+
+        // nothing routed, the creator wins
+        assert_eq!(block.find_winner(&[0xff; 32]), producer.return_publickey());
+
+        // three quarters of the routing work went through the relay
+        let mut routed_tx = create_fee_transaction(&sender, 3000, 0);
+        routed_tx.add_hop(&sender, relay.return_publickey());
+        let mut direct_tx = create_fee_transaction(&sender, 1000, 0);
+        direct_tx.add_hop(&sender, producer.return_publickey());
+        block.transactions.push(routed_tx.clone());
+        block.transactions.push(direct_tx);
+
+        assert_eq!(block.find_winner(&[0x00; 32]), relay.return_publickey());
+        assert_eq!(block.find_winner(&[0xbf; 32]), relay.return_publickey());
+        assert_eq!(block.find_winner(&[0xc1; 32]), producer.return_publickey());
+
+        // along a longer path the first hop wins twice as often as the next
+        routed_tx.add_hop(&relay, producer.return_publickey());
+        block.transactions = vec![routed_tx];
+        let mut relay_wins = 0;
+        for i in 0..1000u32 {
+            let mut hasher = Sha256::new();
+            hasher.input(&i.to_be_bytes());
+            if block.find_winner(&hasher.result()) == relay.return_publickey() { relay_wins += 1; }
+        }
+        assert!(relay_wins > 600 && relay_wins < 730, "relay won {} of 1000", relay_wins);
+    }
+
+    // pretends the inputs of the transactions were created in block 1
+    fn create_blockchain_with_inputs(transactions: Vec<&Transaction>) -> Blockchain {
+        let mut blockchain = Blockchain::new_with_directory(&create_test_directory());
+        for tx in transactions.iter() {
+            for slip in tx.from.iter() {
+                blockchain.insert_slip(slip.return_index(), 1);
+            }
+        }
+        return blockchain;
+    }
+
+    #[test]
+    fn mempool_orders_by_fee_per_byte_and_evicts_lowest() {
+        let wallet = Wallet::new();
+        let mempool = Mempool::with_limits(100000, 3);
+
+        let low_fee_tx = create_fee_transaction(&wallet, 10, 0);
+        let high_fee_tx = create_fee_transaction(&wallet, 30, 0);
+        let large_tx = create_fee_transaction(&wallet, 40, 1000);
+        let middle_fee_tx = create_fee_transaction(&wallet, 20, 0);
+        let lowest_fee_tx = create_fee_transaction(&wallet, 1, 0);
+        let blockchain = create_blockchain_with_inputs(
+            vec![&low_fee_tx, &high_fee_tx, &large_tx, &middle_fee_tx, &lowest_fee_tx]
+        );
+
+        assert_eq!(mempool.borrow_mut().add_transaction(low_fee_tx.clone(), &blockchain), Ok(()));
+        assert_eq!(mempool.borrow_mut().add_transaction(high_fee_tx.clone(), &blockchain), Ok(()));
+        assert_eq!(mempool.borrow_mut().add_transaction(large_tx.clone(), &blockchain), Ok(()));
+
+        // full, so the lowest paying transaction per byte makes way even
+        // though it pays the most in total
+        assert_eq!(mempool.borrow_mut().add_transaction(middle_fee_tx.clone(), &blockchain), Ok(()));
+        assert_eq!(mempool.borrow().return_transactions(), vec![high_fee_tx.clone(), middle_fee_tx.clone(), low_fee_tx.clone()]);
+        assert_eq!(mempool.borrow_mut().add_transaction(lowest_fee_tx, &blockchain), Err(MempoolRejection::MempoolFull));
+        assert_eq!(mempool.borrow_mut().add_transaction(large_tx.clone(), &blockchain), Err(MempoolRejection::MempoolFull));
+        assert!(!mempool.borrow().is_slip_claimed(&large_tx.from[0]));
+
+        let size = mempool.borrow().return_size();
+        assert_eq!(size, high_fee_tx.return_size() + middle_fee_tx.return_size() + low_fee_tx.return_size());
+
+        // a block with room for two transactions leaves the third pending
+        let (_, publickey) = generate_keys();
+        let mut block = Block::new(Vec::new(), publickey);
+        let max_size = 4 + high_fee_tx.return_size() + middle_fee_tx.return_size();
+        block.bundle_transactions(mempool.borrow().return_transactions(), 0, 0, max_size);
+        assert_eq!(block.transactions.len(), 2);
+
+        mempool.borrow_mut().remove_transactions(&block.transactions);
+        assert_eq!(mempool.borrow().return_transactions(), vec![low_fee_tx.clone()]);
+        assert_eq!(mempool.borrow().return_size(), low_fee_tx.return_size());
+
+        // a replacement with no room leaves what it conflicted with pending
+        let size = high_fee_tx.return_size() + low_fee_tx.return_size();
+        let mempool = Mempool::with_limits(size + 100, 3);
+        assert_eq!(mempool.borrow_mut().add_transaction(high_fee_tx.clone(), &blockchain), Ok(()));
+        assert_eq!(mempool.borrow_mut().add_transaction(low_fee_tx.clone(), &blockchain), Ok(()));
+
+        let mut replacement_tx = low_fee_tx.clone();
+        replacement_tx.to[0].amount = Amount::from_nolan(990);
+        replacement_tx.msg = vec![0; 1000];
+        replacement_tx.sig = wallet.create_signature(replacement_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(replacement_tx, &blockchain), Err(MempoolRejection::MempoolFull));
+        assert_eq!(mempool.borrow().return_transactions(), vec![high_fee_tx.clone(), low_fee_tx.clone()]);
+        assert!(mempool.borrow().is_slip_claimed(&low_fee_tx.from[0]));
+        assert_eq!(mempool.borrow().return_size(), size);
+    }
+
+    #[test]
+    fn mempool_rejects_unknown_and_conflicting_inputs() {
+        let wallet = Wallet::new();
+        let mempool = Mempool::new();
+        let tx = create_fee_transaction(&wallet, 10, 0);
+        let blockchain = create_blockchain_with_inputs(vec![&tx]);
+
+        let unknown_tx = create_fee_transaction(&wallet, 20, 0);
+        assert_eq!(mempool.borrow_mut().add_transaction(unknown_tx, &blockchain), Err(MempoolRejection::UnknownInput));
+
+        let mut forged_tx = tx.clone();
+        forged_tx.to[0].amount = Amount::from_nolan(1);
+        assert_eq!(mempool.borrow_mut().add_transaction(forged_tx, &blockchain), Err(MempoolRejection::InvalidSignature));
+
+        let mut overspending_tx = tx.clone();
+        overspending_tx.to[0].amount = Amount::from_nolan(5000);
+        overspending_tx.sig = wallet.create_signature(overspending_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(overspending_tx, &blockchain), Err(MempoolRejection::Invalid));
+
+        assert_eq!(mempool.borrow_mut().add_transaction(tx.clone(), &blockchain), Ok(()));
+        assert!(mempool.borrow().is_slip_claimed(&tx.from[0]));
+
+        // spending the same input again has to pay more to replace it
+        let mut double_spend_tx = tx.clone();
+        double_spend_tx.timestamp += 1;
+        double_spend_tx.sig = wallet.create_signature(double_spend_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(double_spend_tx.clone(), &blockchain), Err(MempoolRejection::Conflict));
+
+        double_spend_tx.to[0].amount = Amount::from_nolan(990);
+        double_spend_tx.sig = wallet.create_signature(double_spend_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(double_spend_tx.clone(), &blockchain), Ok(()));
+        assert_eq!(mempool.borrow().return_transactions(), vec![double_spend_tx.clone()]);
+
+        // once the input is spent in a block nothing else can claim it
+        mempool.borrow_mut().remove_transactions(&vec![double_spend_tx.clone()]);
+        assert!(!mempool.borrow().is_slip_claimed(&tx.from[0]));
+        assert_eq!(mempool.borrow().return_transaction_length(), 0);
+    }
+
+    #[test]
+    fn mempool_reinjects_transactions_from_unwound_blocks() {
+        let wallet = Wallet::new();
+        let mempool = Mempool::new();
+        let tx = create_fee_transaction(&wallet, 10, 0);
+        let spent_tx = create_fee_transaction(&wallet, 20, 0);
+        let blockchain = create_blockchain_with_inputs(vec![&tx]);
+
+        let (_, publickey) = generate_keys();
+        let mut unwound_block = Block::new(Vec::new(), publickey);
+        unwound_block.transactions.push(tx.clone());
+        unwound_block.transactions.push(spent_tx.clone());
+        unwound_block.transactions.push(create_golden_ticket_transaction(&wallet, &unwound_block, &vec![0; 32]));
+
+        // only what is still spendable on the new chain comes back
+        mempool.borrow_mut().reinject_transactions(&vec![unwound_block], &blockchain);
+        assert_eq!(mempool.borrow().return_transactions(), vec![tx.clone()]);
+
+        let mut blockchain = blockchain;
+        blockchain.insert_slip(tx.from[0].return_index(), 0);
+        mempool.borrow_mut().revalidate(&blockchain);
+        assert_eq!(mempool.borrow().return_transaction_length(), 0);
+    }
+
+    #[test]
+    fn mempool_holds_orphan_blocks_until_their_parent_arrives() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        let mempool = Mempool::new();
+
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let b1 = create_child_block(&genesis_block);
+        let b2 = create_child_block(&b1);
+
+        assert_eq!(mempool.borrow_mut().receive_block(genesis_block, &mut blockchain, &mut wallet), AddBlockResult::Extended);
+        assert_eq!(mempool.borrow_mut().receive_block(b2.clone(), &mut blockchain, &mut wallet), AddBlockResult::Orphan);
+        assert_eq!(mempool.borrow().return_block_length(), 1);
+
+        assert_eq!(mempool.borrow_mut().receive_block(b1, &mut blockchain, &mut wallet), AddBlockResult::Extended);
+        assert_eq!(mempool.borrow().return_block_length(), 0);
+        assert_eq!(blockchain.return_latest_hash(), b2.return_block_hash());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // created by the store when the first block is written
+    fn create_test_directory() -> std::path::PathBuf {
+        return std::env::temp_dir().join(format!("saito-test-{}", rand::random::<u32>()));
+    }
+
+    // stamped a day back, so the chains built on it stay behind our clock
+    fn create_genesis_block(publickey: PublicKey) -> Block {
+        let mut block = Block::new(Vec::new(), publickey);
+        block.timestamp -= 86_400_000;
+        return block;
+    }
+
+    // far enough after the previous block that there is no burn fee to pay
+    fn create_child_block(previous_block: &Block) -> Block {
+        let (_, publickey) = generate_keys();
+        let mut block = Block::new(previous_block.return_block_hash(), publickey);
+        block.timestamp = previous_block.timestamp + 21000;
+        block.bundle_with_previous_block(previous_block);
+        block.set_merkle_root();
+        return block;
+    }
+
+    // numbers the transactions and their outputs the way bundling does and
+    // fills in the outputs once the block hash is known
+    fn seal_block(block: &mut Block) {
+        let transactions = std::mem::replace(&mut block.transactions, Vec::new());
+        block.bundle_transactions(transactions, 1, 1, MAX_BLOCK_SIZE);
+        block.set_merkle_root();
+        block.update_slips();
+    }
+
+    #[test]
+    fn block_hash_commits_to_header() {
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let mut block = create_child_block(&genesis_block);
+        block.set_merkle_root();
+        assert!(block.validate(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.treasury = tampered_block.treasury.checked_add(Amount::from_nolan(1)).unwrap();
+        assert_ne!(tampered_block.return_block_hash(), block.return_block_hash());
+        assert!(!tampered_block.validate(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.transactions.push(Transaction::new(TransactionType::Base));
+        assert!(!tampered_block.validate(&genesis_block));
+
+        tampered_block.difficulty = 3.0;
+        assert_ne!(tampered_block.return_block_hash(), block.return_block_hash());
+
+        // outputs carry where they were created, either in the hash or
+        // checked against the block
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(create_fee_transaction(&Wallet::new(), 10, 0));
+        block.transactions.push(create_fee_transaction(&Wallet::new(), 10, 0));
+        seal_block(&mut block);
+        assert!(block.validate(&genesis_block));
+        assert_eq!((block.transactions[1].id, block.transactions[1].to[0].id), (2, 2));
+
+        let mut tampered_block = block.clone();
+        tampered_block.transactions[1].to[0].block_id = u32::max_value();
+        assert!(!tampered_block.validate(&genesis_block));
+        let mut tampered_block = block.clone();
+        tampered_block.transactions[1].to[0].transaction_id = 1;
+        assert!(!tampered_block.validate(&genesis_block));
+        let mut tampered_block = block.clone();
+        tampered_block.transactions[1].to[0].block_hash = genesis_block.return_block_hash();
+        assert!(!tampered_block.validate(&genesis_block));
+        let mut tampered_block = block.clone();
+        tampered_block.transactions[1].id = 3;
+        tampered_block.transactions[1].to[0].transaction_id = 3;
+        tampered_block.transactions[1].to[0].id = 3;
+        assert!(!tampered_block.validate(&genesis_block));
+        tampered_block.set_merkle_root();
+        assert_ne!(tampered_block.return_block_hash(), block.return_block_hash());
+
+        // the message and type of a transaction are in the merkle root
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(Transaction::new(TransactionType::GoldenTicket));
+        block.set_merkle_root();
+        let mut tampered_block = block.clone();
+        tampered_block.transactions[0].msg = vec![1];
+        tampered_block.set_merkle_root();
+        assert_ne!(tampered_block.return_block_hash(), block.return_block_hash());
+        tampered_block.transactions[0].msg = Vec::new();
+        tampered_block.transactions[0].tx_type = TransactionType::Base;
+        tampered_block.set_merkle_root();
+        assert_ne!(tampered_block.return_block_hash(), block.return_block_hash());
+    }
+
+    #[test]
+    fn block_must_pay_burn_fee_from_previous_block() {
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+
+        let block = create_child_block(&genesis_block);
+        assert!(block.paid_burnfee.is_zero());
+        assert!(block.validate_burnfee(&genesis_block));
+
+        // one second after the previous block the full fee is due
+        let (_, creator) = generate_keys();
+        let mut block = Block::new(genesis_block.return_block_hash(), creator);
+        block.timestamp = genesis_block.timestamp + 1000;
+        block.bundle_with_previous_block(&genesis_block);
+        assert_eq!(block.paid_burnfee, genesis_block.burnfee);
+        assert!(!block.validate_burnfee(&genesis_block));
+
+        let mut tx = Transaction::new(TransactionType::Base);
+        let mut slip = Slip::new(creator);
+        slip.amount = block.paid_burnfee;
+        tx.add_from_slip(slip);
+        block.transactions.push(tx);
+        assert!(block.validate_burnfee(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.paid_burnfee = Amount::zero();
+        assert!(!tampered_block.validate_burnfee(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.burnfee = genesis_block.burnfee;
+        assert!(!tampered_block.validate_burnfee(&genesis_block));
+    }
+
+    #[test]
+    fn block_timestamp_must_not_run_ahead_of_our_clock() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        let mempool = Mempool::new();
+
+        let (_, publickey) = generate_keys();
+        let genesis_block = Block::new(Vec::new(), publickey);
+        assert_eq!(mempool.borrow_mut().receive_block(genesis_block.clone(), &mut blockchain, &mut wallet), AddBlockResult::Extended);
+
+        // stamped far enough ahead to owe no burn fee, it is turned away
+        // however well it is formed
+        let block = create_child_block(&genesis_block);
+        assert!(block.paid_burnfee.is_zero());
+        assert!(!block.validate_burnfee(&genesis_block));
+        assert!(!block.validate_header(&genesis_block));
+        assert!(!blockchain.validate_block(&block));
+        assert_eq!(mempool.borrow_mut().receive_block(block, &mut blockchain, &mut wallet), AddBlockResult::Invalid);
+
+        // within the drift it is fine, at the previous block it is not
+        let mut block = Block::new(genesis_block.return_block_hash(), publickey);
+        block.timestamp = genesis_block.timestamp + MAX_TIMESTAMP_DRIFT;
+        assert!(block.validate_timestamp(&genesis_block));
+        block.timestamp = genesis_block.timestamp;
+        assert!(!block.validate_timestamp(&genesis_block));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    fn create_golden_ticket_transaction(wallet: &Wallet, previous_block: &Block, target: &Vec<u8>) -> Transaction {
+        let mut golden_ticket = MiningTarget::new(previous_block, wallet.return_publickey(), 1).create_golden_ticket(vec![0; 4]);
+        golden_ticket.target = target.clone();
+        let (miner_share, node_share) = previous_block.calculate_golden_ticket_shares().unwrap();
+
+        let mut golden_tx = wallet.create_empty_golden_ticket();
+        let mut miner_slip = Slip::new(wallet.return_publickey());
+        miner_slip.amount = miner_share;
+        golden_tx.add_to_slip(miner_slip);
+        let mut node_slip = Slip::new(previous_block.find_winner(&golden_ticket.return_solution()));
+        node_slip.amount = node_share;
+        golden_tx.add_to_slip(node_slip);
+
+        golden_tx.msg = golden_ticket.to_bytes();
+        golden_tx.sig = wallet.create_signature(golden_tx.return_signature_source().as_slice());
+        return golden_tx;
+    }
+
+    #[test]
+    fn block_validates_golden_ticket_against_previous_block() {
+        let wallet = Wallet::new();
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let target = genesis_block.return_block_hash();
+
+        let golden_tx = create_golden_ticket_transaction(&wallet, &genesis_block, &target);
+        let ticket = GoldenTicket::from_bytes(&golden_tx.msg).unwrap();
+        assert!(is_valid_solution(&ticket.return_solution(), &target, genesis_block.difficulty));
+        assert!(!is_valid_solution(&ticket.return_solution(), &target, 33.0));
+
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(golden_tx.clone());
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert!(block.validate(&genesis_block));
+
+        // a second ticket for the same block is left out of it, and turned
+        // away by a block that carries it anyway
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(golden_tx.clone());
+        block.transactions.push(create_golden_ticket_transaction(&Wallet::new(), &genesis_block, &target));
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert_eq!(block.transactions.len(), 1);
+        assert!(block.validate(&genesis_block));
+        block.transactions.push(create_golden_ticket_transaction(&Wallet::new(), &genesis_block, &target));
+        assert!(!block.validate_golden_ticket(&genesis_block));
+
+        // a ticket solving some other block
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(create_golden_ticket_transaction(&wallet, &genesis_block, &vec![0; 32]));
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert!(!block.validate(&genesis_block));
+
+        // a node share paid to anyone but the winner
+        let mut misdirected_tx = golden_tx.clone();
+        misdirected_tx.to[1].address = wallet.return_publickey();
+        misdirected_tx.sig = wallet.create_signature(misdirected_tx.return_signature_source().as_slice());
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(misdirected_tx);
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert!(!block.validate(&genesis_block));
+
+        // a miner paying themselves more than their share
+        let mut overpaid_tx = golden_tx.clone();
+        overpaid_tx.to[0].amount = overpaid_tx.to[0].amount.checked_add(Amount::from_nolan(1)).unwrap();
+        overpaid_tx.sig = wallet.create_signature(overpaid_tx.return_signature_source().as_slice());
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(overpaid_tx);
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert!(!block.validate(&genesis_block));
+
+        // a relay changing the vote breaks the signature and the solution
+        let mut revoted_tx = golden_tx.clone();
+        let mut revoted_ticket = ticket.clone();
+        revoted_ticket.vote = 0;
+        revoted_tx.msg = revoted_ticket.to_bytes();
+        assert!(!revoted_tx.verify_signature());
+        assert_ne!(revoted_ticket.return_solution(), ticket.return_solution());
+
+        // fees are only paid through golden tickets
+        let mut fee_tx = Transaction::new(TransactionType::Fee);
+        let mut fee_input = Slip::new(wallet.return_publickey());
+        fee_input.amount = Amount::from_saito(1);
+        fee_tx.add_from_slip(fee_input);
+        let mut fee_output = Slip::new(wallet.return_publickey());
+        fee_output.amount = Amount::from_saito(1);
+        fee_tx.add_to_slip(fee_output);
+        fee_tx.sig = wallet.create_signature(fee_tx.return_signature_source().as_slice());
+        assert!(fee_tx.validate());
+
+        let mut minting_fee_tx = fee_tx.clone();
+        minting_fee_tx.to[0].amount = Amount::from_saito(2);
+        assert!(!minting_fee_tx.validate());
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(fee_tx.clone());
+        seal_block(&mut block);
+        assert!(!block.validate(&genesis_block));
+        let blockchain = create_blockchain_with_inputs(vec![&fee_tx]);
+        assert_eq!(Mempool::new().borrow_mut().add_transaction(fee_tx, &blockchain), Err(MempoolRejection::Invalid));
+    }
+
+    #[test]
+    fn mempool_admits_one_valid_golden_ticket_for_the_tip() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        let mempool = Mempool::new();
+
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let target = genesis_block.return_block_hash();
+        assert_eq!(mempool.borrow_mut().receive_block(genesis_block.clone(), &mut blockchain, &mut wallet), AddBlockResult::Extended);
+
+        // a ticket that would make the next block invalid never gets in
+        let golden_tx = create_golden_ticket_transaction(&wallet, &genesis_block, &target);
+        let mut overpaid_tx = golden_tx.clone();
+        overpaid_tx.to[0].amount = overpaid_tx.to[0].amount.checked_add(Amount::from_nolan(1)).unwrap();
+        overpaid_tx.sig = wallet.create_signature(overpaid_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(overpaid_tx, &blockchain), Err(MempoolRejection::Invalid));
+
+        let mut misdirected_tx = golden_tx.clone();
+        misdirected_tx.to[1].address = wallet.return_publickey();
+        misdirected_tx.sig = wallet.create_signature(misdirected_tx.return_signature_source().as_slice());
+        assert_eq!(mempool.borrow_mut().add_transaction(misdirected_tx, &blockchain), Err(MempoolRejection::Invalid));
+
+        let stale_tx = create_golden_ticket_transaction(&wallet, &genesis_block, &vec![0; 32]);
+        assert_eq!(mempool.borrow_mut().add_transaction(stale_tx, &blockchain), Err(MempoolRejection::Expired));
+
+        // and only the first valid ticket for the tip is kept
+        assert_eq!(mempool.borrow_mut().add_transaction(golden_tx.clone(), &blockchain), Ok(()));
+        let other_golden_tx = create_golden_ticket_transaction(&Wallet::new(), &genesis_block, &target);
+        assert_eq!(mempool.borrow_mut().add_transaction(other_golden_tx, &blockchain), Err(MempoolRejection::Conflict));
+        assert_eq!(mempool.borrow().return_transactions(), vec![golden_tx]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn block_difficulty_follows_golden_ticket_vote() {
+        let wallet = Wallet::new();
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+
+        // without a golden ticket nothing changes
+        let mut block = create_child_block(&genesis_block);
+        block.calculate_difficulty(&genesis_block);
+        assert_eq!((block.difficulty, block.paysplit), (genesis_block.difficulty, genesis_block.paysplit));
+
+        // the miner wants more difficulty than the chain has so votes it up
+        let mut block = create_child_block(&genesis_block);
+        block.transactions.push(create_golden_ticket_transaction(&wallet, &genesis_block, &genesis_block.return_block_hash()));
+        block.calculate_difficulty(&genesis_block);
+        seal_block(&mut block);
+        assert_eq!(block.difficulty, genesis_block.difficulty + 0.01);
+        assert_eq!(block.paysplit, genesis_block.paysplit + 0.01);
+        assert!(block.validate(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.difficulty = genesis_block.difficulty;
+        assert!(!tampered_block.validate(&genesis_block));
+
+        let mut tampered_block = block.clone();
+        tampered_block.paysplit = 1.0;
+        assert!(!tampered_block.validate(&genesis_block));
+    }
+
+    #[test]
+    fn blockchain_reorganizes_onto_heavier_fork() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let a1 = create_child_block(&genesis_block);
+        let b1 = create_child_block(&genesis_block);
+        let b2 = create_child_block(&b1);
+
+        assert_eq!(blockchain.add_block(genesis_block, &mut wallet), AddBlockResult::Extended);
+        assert_eq!(blockchain.add_block(a1.clone(), &mut wallet), AddBlockResult::Extended);
+        blockchain.add_block(b1.clone(), &mut wallet);
+        blockchain.add_block(b2.clone(), &mut wallet);
+
+        assert_eq!(blockchain.return_latest_hash(), b2.return_block_hash());
+        assert_eq!(blockchain.return_blocks_length(), 3);
+        assert_eq!(blockchain.return_last_block_id(), 3);
+        assert_eq!(blockchain.index[&a1.return_block_hash()].lc, false);
+        assert_eq!(blockchain.index[&b1.return_block_hash()].lc, true);
+        assert_eq!(blockchain.add_block(a1.clone(), &mut wallet), AddBlockResult::Duplicate);
+        assert_eq!(blockchain.get_block_by_hash(&a1.return_block_hash()), Some(a1));
+        assert_eq!(blockchain.get_block_by_id(2), Some(b1));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn blockchain_validates_every_block_after_genesis() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        let mempool = Mempool::new();
+
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let b1 = create_child_block(&genesis_block);
+
+        // a genesis block has to start from the initial state
+        let mut minting_genesis_block = genesis_block.clone();
+        minting_genesis_block.treasury = minting_genesis_block.treasury.checked_add(Amount::from_saito(1)).unwrap();
+        assert!(!blockchain.validate_block(&minting_genesis_block));
+        assert_eq!(mempool.borrow_mut().receive_block(minting_genesis_block, &mut blockchain, &mut wallet), AddBlockResult::Invalid);
+
+        // and nothing else starts the chain, a block waiting on its parent
+        // is only held in memory
+        assert_eq!(mempool.borrow_mut().receive_block(b1.clone(), &mut blockchain, &mut wallet), AddBlockResult::Orphan);
+        assert!(blockchain.index.is_empty());
+        assert!(mempool.borrow().is_block_held(&b1.return_block_hash()));
+        assert!(!blockchain.store.contains(&b1.return_block_hash()));
+        assert_eq!(mempool.borrow_mut().receive_block(genesis_block.clone(), &mut blockchain, &mut wallet), AddBlockResult::Extended);
+        assert_eq!(blockchain.return_latest_hash(), b1.return_block_hash());
+        assert!(!blockchain.validate_block(&Block::new(Vec::new(), publickey)));
+
+        // the second block is checked like any other, on a fork as well
+        let mut overpaid_tx = create_golden_ticket_transaction(&wallet, &genesis_block, &genesis_block.return_block_hash());
+        overpaid_tx.to[0].amount = overpaid_tx.to[0].amount.checked_add(Amount::from_nolan(1)).unwrap();
+        overpaid_tx.sig = wallet.create_signature(overpaid_tx.return_signature_source().as_slice());
+        let mut fork_block = create_child_block(&genesis_block);
+        fork_block.transactions.push(overpaid_tx);
+        fork_block.calculate_difficulty(&genesis_block);
+        seal_block(&mut fork_block);
+        assert!(!blockchain.validate_block(&fork_block));
+        let fork_hash = fork_block.return_block_hash();
+        assert_eq!(mempool.borrow_mut().receive_block(fork_block, &mut blockchain, &mut wallet), AddBlockResult::Invalid);
+        assert!(!blockchain.store.contains(&fork_hash));
+        assert!(blockchain.store.contains(&b1.return_block_hash()));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn blockchain_marks_fork_invalid_when_reorganization_fails_partway() {
+        let (_, publickey) = generate_keys();
+        let genesis_block = create_genesis_block(publickey);
+        let a1 = create_child_block(&genesis_block);
+        let a2 = create_child_block(&a1);
+        let b1 = create_child_block(&genesis_block);
+
+        // spends a slip that does not exist, which is only found once the
+        // fork is wound
+        let mut b2 = create_child_block(&b1);
+        b2.transactions.push(create_fee_transaction(&Wallet::new(), 10, 0));
+        seal_block(&mut b2);
+        let b3 = create_child_block(&b2);
+
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        for block in vec![&genesis_block, &a1, &a2, &b1, &b2, &b3] {
+            blockchain.add_block(block.clone(), &mut wallet);
+        }
+
+        // what is valid of the fork is lighter, so our chain is restored and
+        // the rest of the fork is never tried again
+        assert_eq!(blockchain.return_latest_hash(), a2.return_block_hash());
+        assert_eq!(blockchain.return_blocks_length(), 3);
+        assert!(blockchain.index.contains_key(&b1.return_block_hash()));
+        assert!(!blockchain.index.contains_key(&b2.return_block_hash()));
+        assert_eq!(blockchain.add_block(b2.clone(), &mut wallet), AddBlockResult::Invalid);
+        assert_eq!(blockchain.add_block(b3.clone(), &mut wallet), AddBlockResult::Invalid);
+        assert_eq!(blockchain.add_block(create_child_block(&b3), &mut wallet), AddBlockResult::Invalid);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // what is valid of the fork is kept when it outweighs our chain
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        for block in vec![&genesis_block, &a1, &a2, &b1] {
+            blockchain.add_block(block.clone(), &mut wallet);
+        }
+        blockchain.index.get_mut(&b1.return_block_hash()).unwrap().burnfee = Amount::from_saito(1);
+        assert_eq!(blockchain.add_block(b2.clone(), &mut wallet), AddBlockResult::Reorganized(vec![a2.clone(), a1.clone()]));
+        assert_eq!(blockchain.return_latest_hash(), b1.return_block_hash());
+        assert_eq!(blockchain.return_last_block_id(), 2);
+        assert!(!blockchain.index[&a1.return_block_hash()].lc);
+        assert!(!blockchain.index.contains_key(&b2.return_block_hash()));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn blockchain_keeps_only_the_latest_bodies_in_memory() {
+        let directory = create_test_directory();
+        let mut blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+
+        let (_, publickey) = generate_keys();
+        let mut chain = vec![create_genesis_block(publickey)];
+        for _ in 0..MAX_BLOCKS_IN_MEMORY + 10 {
+            let block = create_child_block(chain.last().unwrap());
+            chain.push(block);
+        }
+        for block in chain.iter() {
+            assert_eq!(blockchain.add_block(block.clone(), &mut wallet), AddBlockResult::Extended);
+        }
+        assert_eq!(blockchain.blocks.borrow().len(), MAX_BLOCKS_IN_MEMORY);
+        assert_eq!(blockchain.return_blocks_length(), chain.len());
+
+        // a node restarting on the same store only holds the latest bodies
+        let mut restarted_blockchain = Blockchain::new_with_directory(&directory);
+        let mut wallet = Wallet::new();
+        restarted_blockchain.initialize(&mut wallet).unwrap();
+        assert_eq!(restarted_blockchain.blocks.borrow().len(), MAX_BLOCKS_IN_MEMORY);
+        assert_eq!(restarted_blockchain.blocks.borrow().last(), chain.last());
+        assert_eq!(restarted_blockchain.get_block_by_id(1).as_ref(), chain.first());
+        assert_eq!(restarted_blockchain.get_header_by_hash(&chain[1].return_block_hash()).unwrap().return_header(), chain[1].return_header());
+
+        // a fork from below the blocks in memory reads the unwound bodies
+        // back from the store
+        let mut fork = vec![create_child_block(&chain[2])];
+        while fork.len() < chain.len() - 2 {
+            let block = create_child_block(fork.last().unwrap());
+            fork.push(block);
+        }
+        for block in fork.iter() {
+            restarted_blockchain.add_block(block.clone(), &mut wallet);
+        }
+        assert_eq!(restarted_blockchain.return_latest_hash(), fork.last().unwrap().return_block_hash());
+        assert_eq!(restarted_blockchain.blocks.borrow().last(), fork.last());
+        assert_eq!(restarted_blockchain.get_block_by_id(4).as_ref(), fork.first());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
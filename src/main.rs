@@ -1,92 +1,137 @@
-use saito::{Mempool, Blockchain, BurnFee, Wallet, Miner, Block, create_merkle_root};
+use saito::{Mempool, Blockchain, BurnFee, Wallet, Miner, Network, RpcServer, Block, AddBlockResult, Transaction};
 
-use std::cell::{RefCell, RefMut};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::env;
+use std::path::Path;
 
 use std::{thread, time};
 
 fn main() {
     println!("Running Saito");
 
-    let mut mempool = Mempool::new();
+    let mempool = Mempool::new();
     let mut blockchain = Blockchain::new();
     let mut burnfee = BurnFee::new();
-    let mut wallet = Wallet::new();
-    let mut miner = Miner::new();
+    // the wallet is kept encrypted on disk so we keep mining to the same
+    // address across restarts, never under an empty passphrase
+    let passphrase = env::var("SAITO_WALLET_PASSPHRASE").unwrap_or_default();
+    if passphrase.is_empty() {
+        println!("SAITO_WALLET_PASSPHRASE IS NOT SET, SHUTTING DOWN");
+        return;
+    }
+    let mut wallet = match Wallet::load_or_create(Path::new("data/wallet.dat"), &passphrase) {
+        Ok(wallet) => wallet,
+        Err(error) => {
+            println!("COULD NOT OPEN WALLET, SHUTTING DOWN: {:?}", error);
+            return;
+        }
+    };
+    // mine on as many threads as we are given, or one for each core
+    let mut miner = match env::var("SAITO_MINER_THREADS").ok().and_then(|threads| threads.parse::<usize>().ok()) {
+        Some(threads) => Miner::with_threads(wallet.return_publickey(), threads),
+        None => Miner::new(wallet.return_publickey()),
+    };
+    println!("MINING ON {} THREADS", miner.return_thread_count());
 
     
     // Initialize our blockchain state and start mining
-    blockchain.initialize(&mut wallet);
-    miner.initialize(&mempool, &blockchain.blocks.borrow_mut(), &wallet, &burnfee);
+    if let Err(err) = blockchain.initialize(&mut wallet) {
+        println!("BLOCK STORE RECOVERY FAILED, SHUTTING DOWN -- {}", err);
+        return;
+    }
+    if let Some(latest_block) = blockchain.blocks.borrow().last() {
+        burnfee = BurnFee::from_block(latest_block);
+        miner.update_target(latest_block);
+    }
 
     let public_key_base_58 = wallet.return_base58();
     println!("YOUR PUBLICKEY: {}", public_key_base_58);
 
-    loop {
-        let num_tx_in_mempool = mempool.borrow_mut().return_transaction_length();
-
-        if (burnfee.return_current_burnfee() <= 0.0 && num_tx_in_mempool > 0) || blockchain.return_last_block_id() == 0 {
-            miner.stop_mining();
-
-            let mut previous_hash: Vec<u8> = Vec::new();
-
-            if blockchain.return_blocks_length() > 0 {
-                previous_hash = blockchain.return_previous_hash();
-            }
-
-            let mut block = Block::new(previous_hash, wallet.return_publickey());
-
-            // transfer all of the transactions of the mempool into our block
-            block.bundle(
-                &blockchain.blocks.borrow_mut(),
-                mempool.borrow_mut().return_transactions(),
-                blockchain.return_last_tx_id(),
-                blockchain.return_last_slip_id(),
-            );
-
-            let last_tx_id: u32 = blockchain.return_last_tx_id() + block.return_tx_len();
-            let last_slip_id: u32 = blockchain.return_last_slip_id() + block.return_slip_len();
+    // listen for peers and connect to those we were given, as a comma
+    // separated list of addresses
+    let mut network = Network::new(wallet.return_publickey());
+    network.set_tip(&blockchain);
+    let listen_address = env::var("SAITO_LISTEN").unwrap_or("0.0.0.0:12101".to_string());
+    match network.listen(listen_address.as_str()) {
+        Ok(address) => println!("LISTENING FOR PEERS ON {}", address),
+        Err(err) => println!("COULD NOT LISTEN FOR PEERS -- {}", err),
+    }
+    let peer_addresses = env::var("SAITO_PEERS").unwrap_or_default();
+    for peer_address in peer_addresses.split(',').filter(|address| !address.is_empty()) {
+        if let Err(err) = network.connect(peer_address) {
+            println!("COULD NOT CONNECT TO PEER {} -- {}", peer_address, err);
+        }
+    }
 
-            // clear the mempool afterwards
-            mempool.borrow_mut().clear_tx_mempool();
+    // answer JSON-RPC calls from local tooling, the wallet methods only to
+    // callers holding the token
+    let rpc_token = env::var("SAITO_RPC_TOKEN").ok().filter(|token| !token.is_empty());
+    if rpc_token.is_none() {
+        println!("SAITO_RPC_TOKEN IS NOT SET, RPC WALLET METHODS ARE DISABLED");
+    }
+    let rpc = RpcServer::new(rpc_token);
+    let rpc_address = env::var("SAITO_RPC").unwrap_or("127.0.0.1:12102".to_string());
+    match rpc.listen(rpc_address.as_str()) {
+        Ok(address) => println!("LISTENING FOR RPC CALLS ON {}", address),
+        Err(err) => println!("COULD NOT LISTEN FOR RPC CALLS -- {}", err),
+    }
 
-            // create merkle root for block once transactions are collected
-            // block.merkle_root = create_merkle_root(block.transactions.clone());
-            block.set_merkle_root();
+    loop {
+        let num_tx_in_mempool = mempool.borrow_mut().return_transaction_length();
 
-            let current_block_timestamp = block.return_timestamp();
+        // a node with peers takes its genesis block from them, and nobody
+        // produces blocks while they are still catching up
+        let needs_genesis = blockchain.return_last_block_id() == 0 && peer_addresses.is_empty();
+        let can_produce = !network.is_syncing() && blockchain.return_last_block_id() > 0;
 
-            burnfee.adjust(current_block_timestamp);
-            burnfee.set_timestamp(current_block_timestamp);
-            burnfee.set_last_block_delta(current_block_timestamp);
+        if (can_produce && burnfee.return_current_burnfee().is_zero() && num_tx_in_mempool > 0) || needs_genesis {
+            miner.stop();
 
+            // fill our block from the mempool, best paying transactions first
+            let mut block = create_block(&blockchain, &wallet, mempool.borrow().return_transactions());
             println!("{:?}", block);
 
-            if !blockchain.validate_block(&block) { 
-                println!("BLOCK INVALID, SHUTTING DOWN");
-                return;
+            // whatever the block can not carry on its own is dropped from the
+            // mempool and the block is filled again from what is left, and
+            // failing that it goes out empty
+            if !blockchain.validate_block(&block) {
+                println!("BLOCK INVALID, DROPPING OFFENDING TRANSACTIONS");
+                let offending_transactions: Vec<Transaction> = block.transactions.iter()
+                    .filter(|tx| !blockchain.validate_block(&create_block(&blockchain, &wallet, vec![(*tx).clone()])))
+                    .cloned()
+                    .collect();
+                mempool.borrow_mut().remove_transactions(&offending_transactions);
+                block = create_block(&blockchain, &wallet, mempool.borrow().return_transactions());
+                if !blockchain.validate_block(&block) {
+                    block = create_block(&blockchain, &wallet, Vec::new());
+                }
+                if !blockchain.validate_block(&block) {
+                    println!("BLOCK STILL INVALID, TRYING AGAIN LATER");
+                    thread::sleep(time::Duration::from_millis(1000));
+                    continue;
+                }
             }
 
-            // update our slips
-            block.update_slips();
-
-            // process them into our wallet afterwards 
-            wallet.process_payment(&block.transactions);
-            println!("CURRENT BALANCE: {}", wallet.return_balance());
-
+            // add to the chain, which processes the block into our wallet,
+            // whatever did not fit in the block waits for the next one
             //block.save();
-            blockchain.add_block(block);
+            match mempool.borrow_mut().receive_block(block, &mut blockchain, &mut wallet) {
+                AddBlockResult::Extended | AddBlockResult::Reorganized(_) => {
+                    println!("Block has been added to the chain!");
+                },
+                _ => {
+                    println!("BLOCK NOT ON LONGEST CHAIN");
+                }
+            }
+            println!("CURRENT BALANCE: {}", wallet.return_balance());
 
-            // Possibly add these to block?
-            blockchain.increment_block_id();
-            blockchain.update_tx_id(last_tx_id);
-            blockchain.update_slip_id(last_slip_id);
+            // the next burn fee is priced from the tip of the chain
+            burnfee = BurnFee::from_block(blockchain.blocks.borrow().last().unwrap());
 
-            println!("Block has been added to the chain!");
+            network.set_tip(&blockchain);
+            network.announce_block(blockchain.blocks.borrow().last().unwrap(), None);
 
 
-            // need to borrow block and implement it
-            miner.start_mining(&mempool, &blockchain.blocks.borrow_mut().last().unwrap(), &wallet, &burnfee);
+            miner.update_target(blockchain.blocks.borrow().last().unwrap());
 
             println!("STARTING MINING ON NEW BLOCK");
 
@@ -94,11 +139,60 @@ fn main() {
         } else {
             let one_second = time::Duration::from_millis(1000);
             thread::sleep(one_second);
-            println!("FEE -- {:.8}", burnfee.return_current_burnfee());
+            println!("FEE -- {}", burnfee.return_current_burnfee());
+
+            // blocks from our peers move the tip we price and mine against
+            let latest_hash = blockchain.return_latest_hash();
+            network.process_events(&mut mempool.borrow_mut(), &mut blockchain, &mut wallet);
+            if blockchain.return_latest_hash() != latest_hash {
+                let blocks = blockchain.blocks.borrow();
+                burnfee = BurnFee::from_block(blocks.last().unwrap());
+                miner.update_target(blocks.last().unwrap());
+            }
+
+            rpc.process_requests(&mut mempool.borrow_mut(), &blockchain, &mut wallet, &burnfee, &network);
+
+            // golden tickets for a block that is no longer the tip are stale
+            while let Ok(golden_ticket) = miner.return_results().try_recv() {
+                let blocks = blockchain.blocks.borrow();
+                let previous_block = blocks.last().unwrap();
+                if miner.return_target(previous_block).is_solved_by(&golden_ticket) {
+                    if let Some(golden_tx) = miner.create_golden_ticket_transaction(&golden_ticket, previous_block, &wallet) {
+                        match mempool.borrow_mut().add_transaction(golden_tx.clone(), &blockchain) {
+                            Ok(()) => {
+                                wallet.mark_pending(&golden_tx);
+                                network.announce_transaction(&golden_tx, None);
+                            },
+                            Err(reason) => println!("GOLDEN TICKET REJECTED: {:?}", reason),
+                        }
+                    }
+                }
+            }
+            println!("HASH RATE -- {:.0}/s", miner.return_stats().return_hash_rate());
         }
     }
-
 }
 
+// a block on our tip carrying the given transactions, in the order given for
+// as long as they fit
+fn create_block(blockchain: &Blockchain, wallet: &Wallet, transactions: Vec<Transaction>) -> Block {
+    let mut previous_hash: Vec<u8> = Vec::new();
 
+    if blockchain.return_blocks_length() > 0 {
+        previous_hash = blockchain.return_previous_hash();
+    }
 
+    let mut block = Block::new(previous_hash, wallet.return_publickey());
+    block.bundle(
+        &blockchain.blocks.borrow_mut(),
+        transactions,
+        blockchain.return_last_tx_id(),
+        blockchain.return_last_slip_id(),
+    );
+
+    // create merkle root for block once transactions are collected, then
+    // update our slips, which are checked against the block hash
+    block.set_merkle_root();
+    block.update_slips();
+    return block;
+}
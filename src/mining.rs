@@ -0,0 +1,449 @@
+// Golden ticket mining on a pool of worker threads
+//
+// The node loop owns a Miner and talks to its workers through messages, so
+// mining never blocks block production or anything else the node does.
+// Solutions come back over the results channel as GoldenTickets, which the
+// node turns into a golden ticket transaction with its wallet.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use digest::Digest;
+use rand::{Rng, thread_rng};
+use secp256k1::PublicKey;
+use sha2::Sha256;
+
+use crate::{is_valid_solution, Amount, Block, GoldenTicket, Slip, Transaction, TransactionType, Wallet};
+
+// attempts between checks for new messages
+const MINING_BATCH_SIZE: u64 = 1000;
+
+// what the workers are searching for, a solution to the previous block at
+// its difficulty
+#[derive(Debug, Clone)]
+pub struct MiningTarget {
+    previous_hash: Vec<u8>,
+    difficulty: f32,
+    vote: u8,
+    publickey: PublicKey,
+}
+
+impl MiningTarget {
+    pub fn new(previous_block: &Block, publickey: PublicKey, vote: u8) -> MiningTarget {
+        return MiningTarget {
+            previous_hash: previous_block.return_block_hash(),
+            difficulty: previous_block.difficulty,
+            vote,
+            publickey,
+        };
+    }
+
+    pub fn create_golden_ticket(&self, random: Vec<u8>) -> GoldenTicket {
+        return GoldenTicket {
+            target: self.previous_hash.clone(),
+            vote: self.vote,
+            random,
+            publickey: self.publickey,
+        };
+    }
+
+    pub fn is_solved_by(&self, golden_ticket: &GoldenTicket) -> bool {
+        return golden_ticket.target == self.previous_hash
+            && is_valid_solution(&golden_ticket.return_solution(), &self.previous_hash, self.difficulty);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    // hashes per second since mining last started
+    pub fn return_hash_rate(&self) -> f64 {
+        let seconds = self.elapsed.as_millis() as f64 / 1000.0;
+        if seconds == 0.0 { return 0.0; }
+        return self.hashes as f64 / seconds;
+    }
+}
+
+// how a worker searches for solutions, each worker has its own
+pub trait MiningStrategy: Send {
+    // the next golden ticket to try
+    fn produce_candidate(&mut self, target: &MiningTarget) -> GoldenTicket;
+
+    fn check(&self, target: &MiningTarget, candidate: &GoldenTicket) -> bool {
+        return target.is_solved_by(candidate);
+    }
+
+    // told how many candidates were tried since the last report and the
+    // solution if one was found
+    fn report(&mut self, _attempts: u64, _solution: Option<&GoldenTicket>) {}
+}
+
+// random u32s, as the miner has always searched
+pub struct RandomStrategy;
+
+impl MiningStrategy for RandomStrategy {
+    fn produce_candidate(&mut self, target: &MiningTarget) -> GoldenTicket {
+        let random_number_bytes: [u8; 4] = thread_rng().gen::<u32>().to_be_bytes();
+        return target.create_golden_ticket(random_number_bytes.to_vec());
+    }
+}
+
+// counts up from start, workers sharing a target each take every step'th
+// nonce so none of them repeat another's work
+pub struct SequentialStrategy {
+    nonce: u64,
+    step: u64,
+}
+
+impl SequentialStrategy {
+    pub fn new(start: u64, step: u64) -> SequentialStrategy {
+        return SequentialStrategy { nonce: start, step: step.max(1) };
+    }
+}
+
+impl MiningStrategy for SequentialStrategy {
+    fn produce_candidate(&mut self, target: &MiningTarget) -> GoldenTicket {
+        let golden_ticket = target.create_golden_ticket(self.nonce.to_be_bytes().to_vec());
+        self.nonce = self.nonce.wrapping_add(self.step);
+        return golden_ticket;
+    }
+}
+
+// a reproducible stream of candidates for tests and simulations, the same
+// seed always finds the same golden ticket for a target no matter which
+// targets were mined before it
+pub struct SeededStrategy {
+    seed: u64,
+    target: Vec<u8>,
+    counter: u64,
+}
+
+impl SeededStrategy {
+    pub fn new(seed: u64) -> SeededStrategy {
+        return SeededStrategy { seed, target: Vec::new(), counter: 0 };
+    }
+}
+
+impl MiningStrategy for SeededStrategy {
+    fn produce_candidate(&mut self, target: &MiningTarget) -> GoldenTicket {
+        if self.target != target.previous_hash {
+            self.target = target.previous_hash.clone();
+            self.counter = 0;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.input(&self.seed.to_be_bytes());
+        hasher.input(&self.target);
+        hasher.input(&self.counter.to_be_bytes());
+        self.counter += 1;
+        return target.create_golden_ticket(hasher.result()[0..8].to_vec());
+    }
+}
+
+// tries at most max_attempts candidates, returning how many were tried and
+// the solution if one was found
+pub fn mine_golden_ticket(strategy: &mut dyn MiningStrategy,
+                          target: &MiningTarget,
+                          max_attempts: u64) -> (u64, Option<GoldenTicket>) {
+    let mut attempts: u64 = 0;
+    let mut solution: Option<GoldenTicket> = None;
+    while attempts < max_attempts && solution.is_none() {
+        let candidate = strategy.produce_candidate(target);
+        attempts += 1;
+
+        if strategy.check(target, &candidate) {
+            solution = Some(candidate);
+        }
+    }
+    strategy.report(attempts, solution.as_ref());
+    return (attempts, solution);
+}
+
+enum MinerMessage {
+    // the flag is shared by every worker on the target so only one of them
+    // reports a solution
+    Start(MiningTarget, Arc<AtomicBool>),
+    Stop,
+    Shutdown,
+}
+
+pub struct Miner {
+    // the difficulty we vote towards, we mine at the difficulty of the chain
+    difficulty: f32,
+    publickey: PublicKey,
+    workers: Vec<Sender<MinerMessage>>,
+    threads: Vec<JoinHandle<()>>,
+    results: Receiver<GoldenTicket>,
+    hashes: Arc<AtomicU64>,
+    started: Instant,
+}
+
+impl Miner {
+    pub fn new(publickey: PublicKey) -> Miner {
+        return Miner::with_threads(publickey, rayon::current_num_threads());
+    }
+
+    pub fn with_threads(publickey: PublicKey, threads: usize) -> Miner {
+        return Miner::with_strategy(publickey, threads, |_| Box::new(RandomStrategy));
+    }
+
+    // create_strategy is called once per worker with the worker's index
+    pub fn with_strategy<F>(publickey: PublicKey, threads: usize, create_strategy: F) -> Miner
+        where F: Fn(usize) -> Box<dyn MiningStrategy> {
+        let (results_sender, results) = channel();
+        let hashes = Arc::new(AtomicU64::new(0));
+
+        let mut workers: Vec<Sender<MinerMessage>> = Vec::new();
+        let mut worker_threads: Vec<JoinHandle<()>> = Vec::new();
+        for worker in 0..threads.max(1) {
+            let (sender, messages) = channel();
+            let results_sender = results_sender.clone();
+            let hashes = hashes.clone();
+            let strategy = create_strategy(worker);
+            workers.push(sender);
+            worker_threads.push(thread::spawn(move || run_worker(messages, results_sender, hashes, strategy)));
+        }
+
+        return Miner {
+            difficulty: 2.0,
+            publickey,
+            workers,
+            threads: worker_threads,
+            results,
+            hashes,
+            started: Instant::now(),
+        };
+    }
+
+    pub fn start(&mut self, target: MiningTarget) {
+        let solved = Arc::new(AtomicBool::new(false));
+        self.hashes.store(0, Ordering::SeqCst);
+        self.started = Instant::now();
+        self.send(|| MinerMessage::Start(target.clone(), solved.clone()));
+    }
+
+    pub fn stop(&mut self) {
+        self.send(|| MinerMessage::Stop);
+    }
+
+    // start over on a new tip of the chain
+    pub fn update_target(&mut self, previous_block: &Block) {
+        let target = self.return_target(previous_block);
+        self.start(target);
+    }
+
+    // our difficulty is only what we vote for, the chain decides
+    pub fn return_target(&self, previous_block: &Block) -> MiningTarget {
+        let mut vote: u8 = 0;
+        if previous_block.difficulty < self.difficulty {
+            vote = 1;
+        }
+        return MiningTarget::new(previous_block, self.publickey, vote);
+    }
+
+    pub fn return_results(&self) -> &Receiver<GoldenTicket> {
+        return &self.results;
+    }
+
+    pub fn return_stats(&self) -> MiningStats {
+        return MiningStats {
+            hashes: self.hashes.load(Ordering::SeqCst),
+            elapsed: self.started.elapsed(),
+        };
+    }
+
+    pub fn return_thread_count(&self) -> usize {
+        return self.workers.len();
+    }
+
+    fn send<F: Fn() -> MinerMessage>(&self, message: F) {
+        for worker in self.workers.iter() {
+            // a worker only hangs up if it panicked, there is nothing to tell it
+            let _ = worker.send(message());
+        }
+    }
+
+    // pays the miner and the winning node the shares the previous block
+    // makes available, validation recomputes these from the previous block
+    // so they must come from the same place
+    pub fn create_golden_ticket_transaction(&self,
+                                            golden_ticket: &GoldenTicket,
+                                            previous_block: &Block,
+                                            wallet: &Wallet) -> Option<Transaction> {
+        // Find winning node, the same way validation will
+        let winning_tx_address = previous_block.find_winner(&golden_ticket.return_solution());
+
+        // Calculate Shares
+        let (miner_share, node_share) = match previous_block.calculate_golden_ticket_shares() {
+            Some(shares) => shares,
+            None => {
+                println!("COULD NOT CALCULATE GOLDEN TICKET SHARES");
+                return None;
+            }
+        };
+
+        println!("CREATING GOLDEN TX");
+        let mut golden_tx: Transaction = match wallet.create_transaction(
+            wallet.publickey,
+            TransactionType::GoldenTicket,
+            Amount::from_nolan(100000),
+            Amount::zero()
+        ) {
+            Some(tx) => tx,
+            None => wallet.create_empty_golden_ticket(),
+        };
+
+        golden_tx.add_to_slip(Slip {
+            address: wallet.publickey,
+            amount: miner_share,
+            block_id: 0,
+            transaction_id: 0,
+            id: 0,
+            block_hash: Vec::new(),
+        });
+
+        golden_tx.add_to_slip(Slip {
+            address: winning_tx_address,
+            amount: node_share,
+            block_id: 0,
+            transaction_id: 0,
+            id: 0,
+            block_hash: Vec::new(),
+        });
+
+        golden_tx.msg = golden_ticket.to_bytes();
+
+        // sign TX
+        golden_tx.sig = wallet.create_signature(golden_tx.return_signature_source().as_slice());
+
+        return Some(golden_tx);
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.send(|| MinerMessage::Shutdown);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_worker(messages: Receiver<MinerMessage>,
+              results: Sender<GoldenTicket>,
+              hashes: Arc<AtomicU64>,
+              mut strategy: Box<dyn MiningStrategy>) {
+    let mut job: Option<(MiningTarget, Arc<AtomicBool>)> = None;
+
+    loop {
+        // wait for work while idle, otherwise pick up whatever was sent
+        // during the last batch
+        let message = match job {
+            None => match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return,
+            },
+            Some(_) => match messages.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            },
+        };
+
+        match message {
+            Some(MinerMessage::Start(target, solved)) => { job = Some((target, solved)); continue; },
+            Some(MinerMessage::Stop) => { job = None; continue; },
+            Some(MinerMessage::Shutdown) => return,
+            None => {},
+        }
+
+        let (target, solved) = match &job {
+            Some(job) => job,
+            None => continue,
+        };
+
+        let (attempts, solution) = mine_golden_ticket(strategy.as_mut(), target, MINING_BATCH_SIZE);
+        hashes.fetch_add(attempts, Ordering::SeqCst);
+
+        if let Some(golden_ticket) = solution {
+            if !solved.swap(true, Ordering::SeqCst) {
+                println!("WE HAVE FOUND A SOLUTION");
+                let _ = results.send(golden_ticket);
+            }
+        }
+
+        // someone has solved this target, wait for the next one
+        if solved.load(Ordering::SeqCst) {
+            job = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_keys;
+
+    #[test]
+    fn miner_delivers_golden_tickets_from_worker_threads() {
+        let (_, publickey) = generate_keys();
+        let previous_block = Block::new(Vec::new(), publickey);
+        let mut miner = Miner::with_threads(publickey, 2);
+        assert_eq!(miner.return_thread_count(), 2);
+
+        miner.update_target(&previous_block);
+        let golden_ticket = miner.return_results().recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(miner.return_target(&previous_block).is_solved_by(&golden_ticket));
+        assert_eq!(golden_ticket.target, previous_block.return_block_hash());
+        assert!(miner.return_stats().hashes > 0);
+
+        // only one of the workers reports a solution to a target
+        assert!(miner.return_results().recv_timeout(Duration::from_millis(200)).is_err());
+
+        // a target too hard to solve keeps the workers busy until stopped
+        let mut hard_block = Block::new(Vec::new(), publickey);
+        hard_block.difficulty = 32.0;
+        miner.update_target(&hard_block);
+        miner.stop();
+        assert!(miner.return_results().recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn seeded_strategy_finds_reproducible_golden_tickets() {
+        let (_, publickey) = generate_keys();
+        let mut previous_block = Block::new(Vec::new(), publickey);
+        previous_block.difficulty = 1.0;
+        let target = MiningTarget::new(&previous_block, publickey, 1);
+
+        let (attempts, golden_ticket) = mine_golden_ticket(&mut SeededStrategy::new(7), &target, 100000);
+        let golden_ticket = golden_ticket.unwrap();
+        assert!(target.is_solved_by(&golden_ticket));
+        assert_eq!(mine_golden_ticket(&mut SeededStrategy::new(7), &target, 100000), (attempts, Some(golden_ticket.clone())));
+
+        // mining another target first does not change what is found for this one
+        let mut other_block = Block::new(Vec::new(), publickey);
+        other_block.difficulty = 1.0;
+        other_block.id = 2;
+        let other_target = MiningTarget::new(&other_block, publickey, 1);
+        assert_ne!(other_target.previous_hash, target.previous_hash);
+        let mut strategy = SeededStrategy::new(7);
+        assert!(mine_golden_ticket(&mut strategy, &other_target, 100000).1.is_some());
+        assert_eq!(mine_golden_ticket(&mut strategy, &target, 100000), (attempts, Some(golden_ticket.clone())));
+
+        // a single seeded worker finds the same ticket in the background
+        let mut miner = Miner::with_strategy(publickey, 1, |_| Box::new(SeededStrategy::new(7)));
+        miner.start(target.clone());
+        assert_eq!(miner.return_results().recv_timeout(Duration::from_secs(10)).unwrap(), golden_ticket);
+
+        let mut strategy = SequentialStrategy::new(1, 2);
+        assert_eq!(strategy.produce_candidate(&target).random, 1u64.to_be_bytes().to_vec());
+        assert_eq!(strategy.produce_candidate(&target).random, 3u64.to_be_bytes().to_vec());
+    }
+}
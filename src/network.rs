@@ -0,0 +1,933 @@
+// Peer to peer networking over TCP
+//
+// Every connection has a reader thread that decodes messages and hands them
+// to the node loop as NetworkEvents, the same way the Miner hands back golden
+// tickets, so the mempool and blockchain are only ever touched from the node
+// loop. Writes go straight to the peer's socket, outside the lock on the
+// peer list.
+//
+// every message is framed as
+//   length            4   of the type and payload
+//   type              1   see PeerMessage
+//   payload
+//
+// payloads:
+//   handshake             protocol version 2, publickey 33, nonce 32,
+//                         tip id 4, tip hash 4 + n
+//   handshake proof       signature 64
+//   announce block        id 4, hash 4 + n
+//   request block         hash 4 + n
+//   block                 block in the wire format
+//   announce transaction  signature 64
+//   request transaction   signature 64
+//   transaction           transaction in the wire format
+//   request headers       max 4, locator 4 + hashes each 4 + n
+//   headers               4 + block headers each 4 + n
+//   request blocks        4 + hashes each 4 + n
+//
+// Both sides send a handshake as soon as they connect, and a peer whose first
+// message is not a handshake for our protocol version is disconnected. The
+// nonce is a challenge, each side answers the other's with a proof signed by
+// the key in its handshake, and a peer is only connected once its proof
+// checks out.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base58::ToBase58;
+use digest::Digest;
+use secp256k1::{Message, PublicKey, Secp256k1, Signature};
+use sha2::Sha256;
+
+use crate::sync::{return_headers_after, ChainSync, SYNC_BATCH_SIZE};
+use crate::wire::{invalid_data, write_bytes, WireReader};
+use crate::{AddBlockResult, Block, Blockchain, Mempool, Transaction, Wallet, MAX_BLOCK_SIZE};
+
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// the largest block plus room for the frame and header
+const MAX_MESSAGE_SIZE: usize = MAX_BLOCK_SIZE + 4096;
+
+// connections we hold, counting those still in the handshake
+const MAX_PEERS: usize = 64;
+
+// events waiting on the node loop, past this a reader thread waits before
+// it reads any more from its peer
+const MAX_PENDING_EVENTS: usize = 1024;
+
+const HANDSHAKE_NONCE_SIZE: usize = 32;
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+const MESSAGE_HANDSHAKE: u8 = 0;
+const MESSAGE_ANNOUNCE_BLOCK: u8 = 1;
+const MESSAGE_REQUEST_BLOCK: u8 = 2;
+const MESSAGE_BLOCK: u8 = 3;
+const MESSAGE_ANNOUNCE_TRANSACTION: u8 = 4;
+const MESSAGE_REQUEST_TRANSACTION: u8 = 5;
+const MESSAGE_TRANSACTION: u8 = 6;
+const MESSAGE_REQUEST_HEADERS: u8 = 7;
+const MESSAGE_HEADERS: u8 = 8;
+const MESSAGE_REQUEST_BLOCKS: u8 = 9;
+const MESSAGE_HANDSHAKE_PROOF: u8 = 10;
+
+pub type PeerId = u64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u16,
+    pub publickey: PublicKey,
+    pub nonce: Vec<u8>,
+    pub tip_id: u32,
+    pub tip_hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    Handshake(Handshake),
+    HandshakeProof(Signature),
+    AnnounceBlock { id: u32, hash: Vec<u8> },
+    RequestBlock(Vec<u8>),
+    Block(Block),
+    AnnounceTransaction(Vec<u8>),
+    RequestTransaction(Vec<u8>),
+    Transaction(Transaction),
+    RequestHeaders { locator: Vec<Vec<u8>>, max: u32 },
+    Headers(Vec<Block>),
+    RequestBlocks(Vec<Vec<u8>>),
+}
+
+impl PeerMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            PeerMessage::Handshake(handshake) => {
+                bytes.push(MESSAGE_HANDSHAKE);
+                bytes.extend(&handshake.version.to_be_bytes());
+                bytes.extend(handshake.publickey.serialize().iter());
+                bytes.extend(&handshake.nonce);
+                bytes.extend(&handshake.tip_id.to_be_bytes());
+                write_bytes(&mut bytes, &handshake.tip_hash);
+            },
+            PeerMessage::HandshakeProof(sig) => {
+                bytes.push(MESSAGE_HANDSHAKE_PROOF);
+                bytes.extend(sig.serialize_compact().iter());
+            },
+            PeerMessage::AnnounceBlock { id, hash } => {
+                bytes.push(MESSAGE_ANNOUNCE_BLOCK);
+                bytes.extend(&id.to_be_bytes());
+                write_bytes(&mut bytes, hash);
+            },
+            PeerMessage::RequestBlock(hash) => {
+                bytes.push(MESSAGE_REQUEST_BLOCK);
+                write_bytes(&mut bytes, hash);
+            },
+            PeerMessage::Block(block) => {
+                bytes.push(MESSAGE_BLOCK);
+                bytes.extend(block.to_bytes());
+            },
+            PeerMessage::AnnounceTransaction(sig) => {
+                bytes.push(MESSAGE_ANNOUNCE_TRANSACTION);
+                bytes.extend(sig);
+            },
+            PeerMessage::RequestTransaction(sig) => {
+                bytes.push(MESSAGE_REQUEST_TRANSACTION);
+                bytes.extend(sig);
+            },
+            PeerMessage::Transaction(tx) => {
+                bytes.push(MESSAGE_TRANSACTION);
+                bytes.extend(tx.to_bytes());
+            },
+            PeerMessage::RequestHeaders { locator, max } => {
+                bytes.push(MESSAGE_REQUEST_HEADERS);
+                bytes.extend(&max.to_be_bytes());
+                write_hashes(&mut bytes, locator);
+            },
+            PeerMessage::Headers(headers) => {
+                bytes.push(MESSAGE_HEADERS);
+                bytes.extend(&(headers.len() as u32).to_be_bytes());
+                for header in headers.iter() {
+                    write_bytes(&mut bytes, &header.header_to_bytes());
+                }
+            },
+            PeerMessage::RequestBlocks(hashes) => {
+                bytes.push(MESSAGE_REQUEST_BLOCKS);
+                write_hashes(&mut bytes, hashes);
+            },
+        }
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<PeerMessage> {
+        let mut reader = WireReader::new(bytes);
+        let message_type = reader.read_u8()?;
+
+        let message = match message_type {
+            MESSAGE_HANDSHAKE => {
+                let mut version = [0; 2];
+                version.copy_from_slice(reader.take(2)?);
+                PeerMessage::Handshake(Handshake {
+                    version: u16::from_be_bytes(version),
+                    publickey: reader.read_publickey()?,
+                    nonce: reader.take(HANDSHAKE_NONCE_SIZE)?.to_vec(),
+                    tip_id: reader.read_u32()?,
+                    tip_hash: reader.read_bytes()?,
+                })
+            },
+            MESSAGE_HANDSHAKE_PROOF => PeerMessage::HandshakeProof(reader.read_signature()?),
+            MESSAGE_ANNOUNCE_BLOCK => {
+                let id = reader.read_u32()?;
+                PeerMessage::AnnounceBlock { id, hash: reader.read_bytes()? }
+            },
+            MESSAGE_REQUEST_BLOCK => PeerMessage::RequestBlock(reader.read_bytes()?),
+            MESSAGE_BLOCK => return Ok(PeerMessage::Block(Block::from_bytes(&bytes[1..])?)),
+            MESSAGE_ANNOUNCE_TRANSACTION => PeerMessage::AnnounceTransaction(reader.take(64)?.to_vec()),
+            MESSAGE_REQUEST_TRANSACTION => PeerMessage::RequestTransaction(reader.take(64)?.to_vec()),
+            MESSAGE_TRANSACTION => return Ok(PeerMessage::Transaction(Transaction::from_bytes(&bytes[1..])?)),
+            MESSAGE_REQUEST_HEADERS => {
+                let max = reader.read_u32()?;
+                PeerMessage::RequestHeaders { locator: read_hashes(&mut reader)?, max }
+            },
+            MESSAGE_HEADERS => {
+                let count = reader.read_u32()?;
+                let mut headers: Vec<Block> = Vec::new();
+                for _ in 0..count {
+                    headers.push(Block::header_from_bytes(&reader.read_bytes()?)?);
+                }
+                PeerMessage::Headers(headers)
+            },
+            MESSAGE_REQUEST_BLOCKS => PeerMessage::RequestBlocks(read_hashes(&mut reader)?),
+            _ => return Err(invalid_data("unknown message type")),
+        };
+
+        reader.finish()?;
+        return Ok(message);
+    }
+}
+
+#[derive(Debug)]
+pub enum NetworkEvent {
+    Connected(PeerId, Handshake),
+    Message(PeerId, PeerMessage),
+    Disconnected(PeerId),
+}
+
+pub struct Network {
+    publickey: PublicKey,
+
+    // what we tell peers in the handshake, shared with the accept thread
+    tip: Arc<Mutex<(u32, Vec<u8>)>>,
+
+    // write halves of connected peers, by the time a peer is in here its
+    // reader thread is running
+    peers: Arc<Mutex<HashMap<PeerId, TcpStream>>>,
+    handshakes: HashMap<PeerId, Handshake>,
+    next_peer_id: Arc<AtomicU64>,
+    sync: ChainSync,
+
+    events: Receiver<NetworkEvent>,
+    sender: SyncSender<NetworkEvent>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Network {
+    pub fn new(publickey: PublicKey) -> Network {
+        let (sender, events) = sync_channel(MAX_PENDING_EVENTS);
+        return Network {
+            publickey,
+            tip: Arc::new(Mutex::new((0, Vec::new()))),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            handshakes: HashMap::new(),
+            next_peer_id: Arc::new(AtomicU64::new(1)),
+            sync: ChainSync::new(),
+            events,
+            sender,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        };
+    }
+
+    // accept peers on addr, returns the address actually bound so that
+    // port 0 can be used in tests
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let connector = self.return_connector();
+        let shutdown = self.shutdown.clone();
+        thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(err) = stream.set_nonblocking(false).and_then(|_| connector.add_peer(stream)) {
+                            println!("FAILED TO ACCEPT PEER -- {}", err);
+                        }
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(err) => println!("FAILED TO ACCEPT PEER -- {}", err),
+                }
+            }
+        });
+
+        return Ok(local_addr);
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<PeerId> {
+        let stream = TcpStream::connect(addr)?;
+        return self.return_connector().add_peer(stream);
+    }
+
+    pub fn set_tip(&self, blockchain: &Blockchain) {
+        *self.tip.lock().unwrap() = (blockchain.return_last_block_id(), blockchain.return_latest_hash());
+    }
+
+    pub fn return_events(&self) -> &Receiver<NetworkEvent> {
+        return &self.events;
+    }
+
+    // peers that have completed the handshake
+    pub fn return_peer_count(&self) -> usize {
+        return self.handshakes.len();
+    }
+
+    pub fn return_handshake(&self, peer: PeerId) -> Option<&Handshake> {
+        return self.handshakes.get(&peer);
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        return self.sync.is_syncing();
+    }
+
+    pub fn send(&self, peer: PeerId, message: &PeerMessage) -> bool {
+        // write to a handle of our own, so a slow peer does not hold up the
+        // threads adding and removing peers
+        let stream = match self.peers.lock().unwrap().get(&peer) {
+            Some(stream) => stream.try_clone(),
+            None => return false,
+        };
+        let written = stream.and_then(|mut stream| write_message(&mut stream, message)).is_ok();
+
+        // the reader thread notices the closed socket and reports it
+        if !written {
+            if let Some(stream) = self.peers.lock().unwrap().remove(&peer) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        return written;
+    }
+
+    pub fn broadcast(&self, message: &PeerMessage, except: Option<PeerId>) {
+        let peers: Vec<PeerId> = self.handshakes.keys().cloned().collect();
+        for peer in peers.into_iter() {
+            if Some(peer) != except {
+                self.send(peer, message);
+            }
+        }
+    }
+
+    pub fn announce_block(&self, block: &Block, except: Option<PeerId>) {
+        self.broadcast(&PeerMessage::AnnounceBlock { id: block.id, hash: block.return_block_hash() }, except);
+    }
+
+    pub fn announce_transaction(&self, tx: &Transaction, except: Option<PeerId>) {
+        self.broadcast(&PeerMessage::AnnounceTransaction(tx.sig.serialize_compact().to_vec()), except);
+    }
+
+    // handle everything the peers have sent since the last call
+    pub fn process_events(&mut self, mempool: &mut Mempool, blockchain: &mut Blockchain, wallet: &mut Wallet) {
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event, mempool, blockchain, wallet);
+        }
+        self.drive_sync(mempool, blockchain, wallet);
+    }
+
+    // connect what has been downloaded, keep the downloads going and start
+    // a sync with whichever peer is furthest ahead of us
+    pub fn drive_sync(&mut self, mempool: &mut Mempool, blockchain: &mut Blockchain, wallet: &mut Wallet) {
+        let mut peers: Vec<PeerId> = self.handshakes.keys().cloned().collect();
+        peers.sort();
+
+        if self.sync.is_syncing() && !self.sync.expire_header_request(&peers) {
+            println!("SYNC INTERRUPTED AT BLOCK {}", blockchain.return_last_block_id());
+            self.sync.abort();
+        }
+
+        // bodies that nobody sends us however often we ask end the sync
+        // as if the peer had sent something invalid
+        if self.sync.is_syncing() && !self.sync.expire_body_requests(&peers) {
+            println!("SYNC FAILED, BLOCKS DID NOT ARRIVE AT BLOCK {}", blockchain.return_last_block_id());
+            self.abandon_sync();
+        }
+
+        if self.sync.is_syncing() {
+            match self.sync.connect_bodies(mempool, blockchain, wallet) {
+                Some(0) => {},
+                Some(_) => {
+                    println!("SYNCING -- BLOCK {} OF {}", blockchain.return_last_block_id(), self.sync.return_target_id());
+                    self.set_tip(blockchain);
+                },
+                None => {
+                    println!("SYNC FAILED, PEER SENT AN INVALID BLOCK");
+                    self.abandon_sync();
+                },
+            }
+        }
+
+        if self.sync.is_complete() {
+            println!("SYNC COMPLETE AT BLOCK {}", blockchain.return_last_block_id());
+
+            // we have everything this peer had to give, so do not sync with
+            // it again until it announces something new
+            let last_block_id = blockchain.return_last_block_id();
+            if let Some(handshake) = self.sync.return_peer().and_then(|peer| self.handshakes.get_mut(&peer)) {
+                handshake.tip_id = handshake.tip_id.min(last_block_id);
+            }
+            self.sync.abort();
+        }
+
+        if !self.sync.is_syncing() {
+            let last_block_id = blockchain.return_last_block_id();
+            let furthest_peer = self.handshakes.iter()
+                .filter(|(_, handshake)| handshake.tip_id > last_block_id + 1)
+                .max_by_key(|(peer, handshake)| (handshake.tip_id, std::cmp::Reverse(**peer)))
+                .map(|(peer, handshake)| (*peer, handshake.tip_id));
+            if let Some((peer, tip_id)) = furthest_peer {
+                println!("SYNCING FROM BLOCK {} TO {}", last_block_id, tip_id);
+                let request = self.sync.start(peer, tip_id, blockchain);
+                self.send(peer, &request);
+            }
+        }
+
+        if let Some((peer, request)) = self.sync.request_headers() {
+            self.send(peer, &request);
+        }
+        let peer_tips: Vec<(PeerId, u32)> = peers.iter().map(|peer| (*peer, self.handshakes[peer].tip_id)).collect();
+        for (peer, request) in self.sync.request_bodies(&peer_tips).into_iter() {
+            self.send(peer, &request);
+        }
+    }
+
+    pub fn handle_event(&mut self, event: NetworkEvent, mempool: &mut Mempool, blockchain: &mut Blockchain, wallet: &mut Wallet) {
+        match event {
+            NetworkEvent::Connected(peer, handshake) => {
+                println!("PEER CONNECTED -- {} AT BLOCK {}", handshake.publickey.serialize().to_base58(), handshake.tip_id);
+
+                // a peer further ahead is synced from in process_events
+                if handshake.tip_id == blockchain.return_last_block_id() + 1 && !blockchain.index.contains_key(&handshake.tip_hash) {
+                    self.send(peer, &PeerMessage::RequestBlock(handshake.tip_hash.clone()));
+                }
+                self.handshakes.insert(peer, handshake);
+            },
+            NetworkEvent::Disconnected(peer) => {
+                println!("PEER DISCONNECTED -- {}", peer);
+                self.handshakes.remove(&peer);
+            },
+            NetworkEvent::Message(peer, message) => {
+                self.handle_message(peer, message, mempool, blockchain, wallet);
+            },
+        }
+    }
+
+    fn handle_message(&mut self, peer: PeerId, message: PeerMessage, mempool: &mut Mempool, blockchain: &mut Blockchain, wallet: &mut Wallet) {
+        match message {
+            PeerMessage::Handshake(handshake) => {
+                // prove we hold the key we announced, once per connection
+                if !self.handshakes.contains_key(&peer) {
+                    let sig = wallet.create_signature(&return_handshake_signature_source(&handshake.nonce));
+                    self.send(peer, &PeerMessage::HandshakeProof(sig));
+                }
+            },
+            PeerMessage::HandshakeProof(_) => {},
+            PeerMessage::AnnounceBlock { id, hash } => {
+                if let Some(handshake) = self.handshakes.get_mut(&peer) {
+                    handshake.tip_id = handshake.tip_id.max(id);
+                }
+                if self.sync.is_syncing() || id > blockchain.return_last_block_id() + 1 { return; }
+                if !blockchain.index.contains_key(&hash) && !mempool.is_block_held(&hash) {
+                    self.send(peer, &PeerMessage::RequestBlock(hash));
+                }
+            },
+            PeerMessage::RequestBlock(hash) => {
+                if let Some(block) = blockchain.get_block_by_hash(&hash) {
+                    self.send(peer, &PeerMessage::Block(block));
+                }
+            },
+            PeerMessage::Block(block) => {
+                if self.sync.is_expected(&block.return_block_hash()) {
+                    self.sync.receive_body(block);
+                    return;
+                }
+
+                let previous_hash = block.previous_hash.clone();
+                match mempool.receive_block(block, blockchain, wallet) {
+                    AddBlockResult::Extended | AddBlockResult::Reorganized(_) => {
+                        self.set_tip(blockchain);
+                        if let Some(latest_block) = blockchain.blocks.borrow().last() {
+                            self.announce_block(latest_block, Some(peer));
+                        }
+                    },
+                    AddBlockResult::Orphan => {
+                        if !self.sync.is_syncing() {
+                            self.send(peer, &PeerMessage::RequestBlock(previous_hash));
+                        }
+                    },
+                    AddBlockResult::Invalid => {
+                        println!("PEER SENT INVALID BLOCK -- {}", peer);
+                    },
+                    _ => {},
+                }
+            },
+            PeerMessage::AnnounceTransaction(sig) => {
+                if !mempool.contains_transaction(&sig) {
+                    self.send(peer, &PeerMessage::RequestTransaction(sig));
+                }
+            },
+            PeerMessage::RequestTransaction(sig) => {
+                if let Some(mut tx) = mempool.return_transaction(&sig) {
+                    // sign the transaction over to the peer so our routing
+                    // work is on its path
+                    if let Some(handshake) = self.handshakes.get(&peer) {
+                        tx.add_hop(wallet, handshake.publickey);
+                    }
+                    self.send(peer, &PeerMessage::Transaction(tx));
+                }
+            },
+            PeerMessage::Transaction(tx) => {
+                match mempool.add_transaction(tx.clone(), blockchain) {
+                    Ok(()) => self.announce_transaction(&tx, Some(peer)),
+                    Err(reason) => println!("PEER TRANSACTION REJECTED: {:?}", reason),
+                }
+            },
+            PeerMessage::RequestHeaders { locator, max } => {
+                self.send(peer, &PeerMessage::Headers(return_headers_after(blockchain, &locator, max)));
+            },
+            PeerMessage::Headers(headers) => {
+                if !self.sync.receive_headers(peer, headers, blockchain) {
+                    println!("PEER SENT INVALID HEADERS -- {}", peer);
+                    if self.sync.return_peer() == Some(peer) {
+                        self.abandon_sync();
+                    }
+                }
+            },
+            PeerMessage::RequestBlocks(hashes) => {
+                for block_hash in hashes.iter().take(SYNC_BATCH_SIZE) {
+                    if let Some(block) = blockchain.get_block_by_hash(block_hash) {
+                        self.send(peer, &PeerMessage::Block(block));
+                    }
+                }
+            },
+        }
+    }
+
+    // stop syncing from a peer that sent us something invalid, and do not
+    // go back to it until it announces a new block
+    fn abandon_sync(&mut self) {
+        if let Some(handshake) = self.sync.return_peer().and_then(|peer| self.handshakes.get_mut(&peer)) {
+            handshake.tip_id = 0;
+        }
+        self.sync.abort();
+    }
+
+    fn return_connector(&self) -> Connector {
+        return Connector {
+            publickey: self.publickey,
+            tip: self.tip.clone(),
+            peers: self.peers.clone(),
+            next_peer_id: self.next_peer_id.clone(),
+            sender: self.sender.clone(),
+        };
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        for stream in self.peers.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// what the accept thread needs to set up a peer
+struct Connector {
+    publickey: PublicKey,
+    tip: Arc<Mutex<(u32, Vec<u8>)>>,
+    peers: Arc<Mutex<HashMap<PeerId, TcpStream>>>,
+    next_peer_id: Arc<AtomicU64>,
+    sender: SyncSender<NetworkEvent>,
+}
+
+impl Connector {
+    fn add_peer(&self, mut stream: TcpStream) -> io::Result<PeerId> {
+        let peer = self.next_peer_id.fetch_add(1, Ordering::SeqCst);
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if peers.len() >= MAX_PEERS {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(io::Error::new(io::ErrorKind::Other, "too many peers"));
+            }
+            peers.insert(peer, stream.try_clone()?);
+        }
+
+        let nonce: Vec<u8> = (0..HANDSHAKE_NONCE_SIZE).map(|_| rand::random::<u8>()).collect();
+        let (tip_id, tip_hash) = self.tip.lock().unwrap().clone();
+        let handshake = PeerMessage::Handshake(Handshake {
+            version: PROTOCOL_VERSION,
+            publickey: self.publickey,
+            nonce: nonce.clone(),
+            tip_id,
+            tip_hash,
+        });
+        if let Err(err) = write_message(&mut stream, &handshake) {
+            self.peers.lock().unwrap().remove(&peer);
+            return Err(err);
+        }
+
+        let peers = self.peers.clone();
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            run_reader(peer, stream, &sender, &nonce);
+            if let Some(stream) = peers.lock().unwrap().remove(&peer) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            let _ = sender.send(NetworkEvent::Disconnected(peer));
+        });
+
+        return Ok(peer);
+    }
+}
+
+// the node loop answers the peer's challenge, and the peer has to answer
+// ours with the next message before it counts as connected
+fn run_reader(peer: PeerId, mut stream: TcpStream, sender: &SyncSender<NetworkEvent>, nonce: &[u8]) {
+    let handshake = match read_message(&mut stream) {
+        Ok(PeerMessage::Handshake(handshake)) if handshake.version == PROTOCOL_VERSION => handshake,
+        _ => return,
+    };
+    if sender.send(NetworkEvent::Message(peer, PeerMessage::Handshake(handshake.clone()))).is_err() { return; }
+
+    match read_message(&mut stream) {
+        Ok(PeerMessage::HandshakeProof(sig)) if verify_handshake_proof(nonce, &sig, &handshake.publickey) => {
+            if sender.send(NetworkEvent::Connected(peer, handshake)).is_err() { return; }
+        },
+        _ => {
+            println!("PEER FAILED THE HANDSHAKE CHALLENGE -- {}", peer);
+            return;
+        },
+    }
+
+    loop {
+        match read_message(&mut stream) {
+            Ok(message) => {
+                if sender.send(NetworkEvent::Message(peer, message)).is_err() { return; }
+            },
+            Err(_) => return,
+        }
+    }
+}
+
+// what a peer signs to prove it holds the key in its handshake, tagged so
+// the signature can not be passed off as anything else
+fn return_handshake_signature_source(nonce: &[u8]) -> Vec<u8> {
+    let mut source = b"SAITO HANDSHAKE".to_vec();
+    source.extend(nonce);
+    return source;
+}
+
+fn verify_handshake_proof(nonce: &[u8], sig: &Signature, publickey: &PublicKey) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.input(return_handshake_signature_source(nonce));
+    let msg = match Message::from_slice(hasher.result().as_slice()) {
+        Ok(msg) => msg,
+        Err(_) => return false,
+    };
+    return Secp256k1::verification_only().verify(&msg, sig, publickey).is_ok();
+}
+
+fn write_hashes(bytes: &mut Vec<u8>, hashes: &Vec<Vec<u8>>) {
+    bytes.extend(&(hashes.len() as u32).to_be_bytes());
+    for hash in hashes.iter() {
+        write_bytes(bytes, hash);
+    }
+}
+
+fn read_hashes(reader: &mut WireReader) -> io::Result<Vec<Vec<u8>>> {
+    let count = reader.read_u32()?;
+    let mut hashes: Vec<Vec<u8>> = Vec::new();
+    for _ in 0..count {
+        hashes.push(reader.read_bytes()?);
+    }
+    return Ok(hashes);
+}
+
+fn write_message(stream: &mut TcpStream, message: &PeerMessage) -> io::Result<()> {
+    let payload = message.to_bytes();
+    let mut frame: Vec<u8> = Vec::with_capacity(4 + payload.len());
+    frame.extend(&(payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    return stream.write_all(&frame);
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<PeerMessage> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length == 0 || length > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("message too large"));
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    return PeerMessage::from_bytes(&payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    use crate::{Amount, Slip, TransactionType};
+
+    struct TestNode {
+        network: Network,
+        mempool: RefCell<Mempool>,
+        blockchain: Blockchain,
+        wallet: Wallet,
+        directory: PathBuf,
+    }
+
+    impl TestNode {
+        fn new() -> TestNode {
+            let directory = std::env::temp_dir().join(format!("saito-network-test-{}", rand::random::<u64>()));
+            let wallet = Wallet::new();
+            return TestNode {
+                network: Network::new(wallet.return_publickey()),
+                mempool: Mempool::new(),
+                blockchain: Blockchain::new_with_directory(&directory),
+                wallet,
+                directory,
+            };
+        }
+
+        fn process_events(&mut self) {
+            self.network.process_events(&mut self.mempool.borrow_mut(), &mut self.blockchain, &mut self.wallet);
+        }
+
+        // produce the next block on our own chain, the way the node does
+        fn produce_block(&mut self) -> Block {
+            let previous_hash = self.blockchain.return_latest_hash();
+            let mut block = Block::new(previous_hash, self.wallet.return_publickey());
+            // a genesis block a day back leaves room for the chain to stay
+            // behind our clock
+            match self.blockchain.blocks.borrow().last() {
+                Some(previous_block) => block.timestamp = previous_block.timestamp + 21000,
+                None => block.timestamp -= 86_400_000,
+            }
+            block.bundle(
+                &self.blockchain.blocks.borrow_mut(),
+                Vec::new(),
+                self.blockchain.return_last_tx_id(),
+                self.blockchain.return_last_slip_id(),
+            );
+            block.set_merkle_root();
+            block.update_slips();
+
+            let result = self.mempool.borrow_mut().receive_block(block.clone(), &mut self.blockchain, &mut self.wallet);
+            assert_eq!(result, AddBlockResult::Extended);
+            self.network.set_tip(&self.blockchain);
+            return block;
+        }
+    }
+
+    impl Drop for TestNode {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    fn process_until<F: Fn(&TestNode, &TestNode) -> bool>(a: &mut TestNode, b: &mut TestNode, condition: F) {
+        let started = Instant::now();
+        while !condition(a, b) {
+            assert!(started.elapsed() < Duration::from_secs(10), "nodes did not converge");
+            a.process_events();
+            b.process_events();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn peer_message_round_trips_through_bytes() {
+        let wallet = Wallet::new();
+        let messages = vec![
+            PeerMessage::Handshake(Handshake {
+                version: PROTOCOL_VERSION,
+                publickey: wallet.return_publickey(),
+                nonce: vec![3; HANDSHAKE_NONCE_SIZE],
+                tip_id: 7,
+                tip_hash: vec![1; 32],
+            }),
+            PeerMessage::HandshakeProof(wallet.create_signature(&[3; HANDSHAKE_NONCE_SIZE])),
+            PeerMessage::AnnounceBlock { id: 7, hash: vec![1; 32] },
+            PeerMessage::RequestBlock(vec![1; 32]),
+            PeerMessage::Block(Block::new(Vec::new(), wallet.return_publickey())),
+            PeerMessage::AnnounceTransaction(vec![2; 64]),
+            PeerMessage::RequestTransaction(vec![2; 64]),
+            PeerMessage::Transaction(Transaction::new(TransactionType::Base)),
+        ];
+        for message in messages.into_iter() {
+            assert_eq!(PeerMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+        assert!(PeerMessage::from_bytes(&[42]).is_err());
+
+        let mut block = Block::new(Vec::new(), wallet.return_publickey());
+        block.transactions.push(Transaction::new(TransactionType::Base));
+        let messages = vec![
+            PeerMessage::RequestHeaders { locator: vec![vec![1; 32], vec![2; 32]], max: 2000 },
+            PeerMessage::Headers(vec![Block::header_from_bytes(&block.header_to_bytes()).unwrap()]),
+            PeerMessage::RequestBlocks(vec![vec![1; 32]]),
+        ];
+        for message in messages.into_iter() {
+            assert_eq!(PeerMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+    }
+
+    // talks to a node over a raw socket, announcing publickey and signing
+    // the node's challenge with wallet
+    fn handshake_with(addr: SocketAddr, publickey: PublicKey, wallet: &Wallet) -> (TcpStream, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let challenge = match read_message(&mut stream).unwrap() {
+            PeerMessage::Handshake(handshake) => handshake.nonce,
+            message => panic!("expected a handshake, got {:?}", message),
+        };
+        let nonce = vec![7; HANDSHAKE_NONCE_SIZE];
+        write_message(&mut stream, &PeerMessage::Handshake(Handshake {
+            version: PROTOCOL_VERSION,
+            publickey,
+            nonce: nonce.clone(),
+            tip_id: 0,
+            tip_hash: Vec::new(),
+        })).unwrap();
+        let sig = wallet.create_signature(&return_handshake_signature_source(&challenge));
+        write_message(&mut stream, &PeerMessage::HandshakeProof(sig)).unwrap();
+        return (stream, nonce);
+    }
+
+    fn process_node_until<F: Fn(&TestNode) -> bool>(node: &mut TestNode, condition: F) {
+        let started = Instant::now();
+        while !condition(node) {
+            assert!(started.elapsed() < Duration::from_secs(10), "node did not settle");
+            node.process_events();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn peer_has_to_prove_it_holds_the_key_in_its_handshake() {
+        let mut a = TestNode::new();
+        let addr = a.network.listen("127.0.0.1:0").unwrap();
+
+        // an impostor claiming someone else's key is answered, and then
+        // dropped without ever counting as a peer
+        let victim = Wallet::new();
+        let (mut stream, nonce) = handshake_with(addr, victim.return_publickey(), &Wallet::new());
+        process_node_until(&mut a, |a| a.network.peers.lock().unwrap().is_empty());
+        assert_eq!(a.network.return_peer_count(), 0);
+        match read_message(&mut stream).unwrap() {
+            PeerMessage::HandshakeProof(sig) => assert!(verify_handshake_proof(&nonce, &sig, &a.wallet.return_publickey())),
+            message => panic!("expected a handshake proof, got {:?}", message),
+        }
+        assert!(read_message(&mut stream).is_err());
+
+        let wallet = Wallet::new();
+        let (_stream, _) = handshake_with(addr, wallet.return_publickey(), &wallet);
+        process_node_until(&mut a, |a| a.network.return_peer_count() == 1);
+        assert_eq!(a.network.handshakes.values().next().unwrap().publickey, wallet.return_publickey());
+    }
+
+    #[test]
+    fn nodes_on_localhost_exchange_blocks_and_transactions() {
+        let mut a = TestNode::new();
+        let mut b = TestNode::new();
+
+        // b shares a's genesis block and is two blocks behind when it
+        // connects, it fetches a's tip and then its parent as an orphan
+        let genesis_block = a.produce_block();
+        b.mempool.borrow_mut().receive_block(genesis_block, &mut b.blockchain, &mut b.wallet);
+        a.produce_block();
+        a.produce_block();
+        let addr = a.network.listen("127.0.0.1:0").unwrap();
+        b.network.connect(addr).unwrap();
+
+        process_until(&mut a, &mut b, |a, b| {
+            a.network.return_peer_count() == 1 && b.blockchain.return_latest_hash() == a.blockchain.return_latest_hash()
+        });
+        assert_eq!(b.blockchain.return_last_block_id(), a.blockchain.return_last_block_id());
+        assert_eq!(b.blockchain.return_blocks_length(), 3);
+
+        // a new block on b is announced to a
+        b.produce_block();
+        b.network.announce_block(b.blockchain.blocks.borrow().last().unwrap(), None);
+        process_until(&mut a, &mut b, |a, b| a.blockchain.return_latest_hash() == b.blockchain.return_latest_hash());
+
+        // a transaction spending an input both chains know about
+        let mut tx = Transaction::new(TransactionType::Base);
+        let mut input = Slip::new(a.wallet.return_publickey());
+        input.amount = Amount::from_nolan(1100);
+        tx.add_from_slip(input.clone());
+        let mut output = Slip::new(a.wallet.return_publickey());
+        output.amount = Amount::from_nolan(1000);
+        tx.add_to_slip(output);
+        tx.sig = a.wallet.create_signature(tx.return_signature_source().as_slice());
+
+        a.blockchain.insert_slip(input.return_index(), 1);
+        b.blockchain.insert_slip(input.return_index(), 1);
+        a.mempool.borrow_mut().add_transaction(tx.clone(), &a.blockchain).unwrap();
+        a.network.announce_transaction(&tx, None);
+
+        let sig = tx.sig.serialize_compact().to_vec();
+        process_until(&mut a, &mut b, |_, b| b.mempool.borrow().contains_transaction(&sig));
+
+        // a signed it over to b on the way
+        let relayed_tx = b.mempool.borrow().return_transaction(&sig).unwrap();
+        assert_eq!(relayed_tx.return_path().len(), 1);
+        assert_eq!(relayed_tx.return_routing_work_for(&b.wallet.return_publickey()), Amount::from_nolan(100));
+    }
+
+    #[test]
+    fn new_node_syncs_the_chain_and_resumes_after_a_disconnect() {
+        let mut a = TestNode::new();
+        let mut b = TestNode::new();
+        for _ in 0..200 {
+            a.produce_block();
+        }
+        let addr = a.network.listen("127.0.0.1:0").unwrap();
+
+        // b starts without a chain, and loses its connection part way
+        b.network.connect(addr).unwrap();
+        process_until(&mut a, &mut b, |_, b| b.blockchain.return_last_block_id() >= 20);
+        assert!(b.network.is_syncing());
+        b.network = Network::new(b.wallet.return_publickey());
+        b.network.set_tip(&b.blockchain);
+
+        b.network.connect(addr).unwrap();
+        process_until(&mut a, &mut b, |a, b| {
+            b.blockchain.return_latest_hash() == a.blockchain.return_latest_hash() && !b.network.is_syncing()
+        });
+        assert_eq!(b.blockchain.return_blocks_length(), 200);
+    }
+}
@@ -0,0 +1,133 @@
+// Building signed payments out of the wallet's slips
+//
+// A payment pays one or more recipients, takes its inputs from the wallet's
+// coin selection and sends whatever is left over after the fee back to the
+// wallet. Building it signs the transaction and marks its inputs pending, so
+// the next payment can not spend them again.
+
+use secp256k1::PublicKey;
+
+use crate::{Amount, Slip, SpendPlan, Transaction, TransactionType, Wallet};
+
+#[derive(Debug, PartialEq)]
+pub enum PaymentError {
+    NoRecipients,
+    AmountOverflow,
+    InsufficientFunds,
+}
+
+pub struct PaymentBuilder<'a> {
+    wallet: &'a mut Wallet,
+    tx_type: TransactionType,
+    recipients: Vec<(PublicKey, Amount)>,
+    fee: Amount,
+    msg: Vec<u8>,
+}
+
+impl<'a> PaymentBuilder<'a> {
+    pub fn new(wallet: &'a mut Wallet) -> PaymentBuilder<'a> {
+        return PaymentBuilder {
+            wallet,
+            tx_type: TransactionType::Base,
+            recipients: Vec::new(),
+            fee: Amount::zero(),
+            msg: Vec::new(),
+        };
+    }
+
+    pub fn to(mut self, publickey: PublicKey, amount: Amount) -> PaymentBuilder<'a> {
+        self.recipients.push((publickey, amount));
+        return self;
+    }
+
+    pub fn fee(mut self, fee: Amount) -> PaymentBuilder<'a> {
+        self.fee = fee;
+        return self;
+    }
+
+    pub fn msg(mut self, msg: Vec<u8>) -> PaymentBuilder<'a> {
+        self.msg = msg;
+        return self;
+    }
+
+    pub fn tx_type(mut self, tx_type: TransactionType) -> PaymentBuilder<'a> {
+        self.tx_type = tx_type;
+        return self;
+    }
+
+    // the inputs, change and fee the payment would use, without building it
+    pub fn dry_run(&self) -> Result<SpendPlan, PaymentError> {
+        if self.recipients.is_empty() { return Err(PaymentError::NoRecipients); }
+
+        let amount = Amount::checked_sum(self.recipients.iter().map(|(_, amount)| *amount))
+            .ok_or(PaymentError::AmountOverflow)?;
+        amount.checked_add(self.fee).ok_or(PaymentError::AmountOverflow)?;
+
+        return self.wallet.dry_run_transaction(self.fee, amount).ok_or(PaymentError::InsufficientFunds);
+    }
+
+    pub fn build(self) -> Result<Transaction, PaymentError> {
+        let plan = self.dry_run()?;
+
+        let mut tx = Transaction::new(self.tx_type);
+        for from_slip in plan.inputs.into_iter() {
+            tx.add_from_slip(from_slip);
+        }
+
+        for (publickey, amount) in self.recipients.iter() {
+            let mut to_slip = Slip::new(*publickey);
+            to_slip.amount = *amount;
+            tx.add_to_slip(to_slip);
+        }
+
+        if !plan.change.is_zero() {
+            let mut change_slip = Slip::new(self.wallet.return_publickey());
+            change_slip.amount = plan.change;
+            tx.add_to_slip(change_slip);
+        }
+
+        tx.msg = self.msg;
+        tx.sig = self.wallet.create_signature(tx.return_signature_source().as_slice());
+
+        self.wallet.mark_pending(&tx);
+
+        return Ok(tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payment_builder_pays_recipients_and_returns_change() {
+        let mut wallet = Wallet::new();
+        let mut input = Slip::new(wallet.return_publickey());
+        input.amount = Amount::from_nolan(1000);
+        wallet.add_input(input.clone());
+
+        let alice = Wallet::new().return_publickey();
+        let bob = Wallet::new().return_publickey();
+
+        let tx = wallet.create_payment()
+            .to(alice, Amount::from_nolan(300))
+            .to(bob, Amount::from_nolan(200))
+            .fee(Amount::from_nolan(50))
+            .msg(b"rent".to_vec())
+            .build()
+            .unwrap();
+
+        assert!(tx.verify_signature());
+        assert_eq!(tx.from, vec![input]);
+        assert_eq!(tx.to.iter().map(|slip| (slip.address, slip.amount.return_nolan())).collect::<Vec<_>>(),
+                   vec![(alice, 300), (bob, 200), (wallet.return_publickey(), 450)]);
+        assert_eq!(tx.return_fees(), Amount::from_nolan(50));
+        assert_eq!(tx.msg, b"rent".to_vec());
+
+        // the input is pending, so a second payment has nothing to spend
+        assert_eq!(wallet.return_available_balance(), Amount::zero());
+        assert_eq!(wallet.return_pending_balance(), Amount::from_nolan(450));
+        assert_eq!(wallet.create_payment().to(alice, Amount::from_nolan(1)).build(), Err(PaymentError::InsufficientFunds));
+        assert_eq!(wallet.create_payment().build(), Err(PaymentError::NoRecipients));
+    }
+}
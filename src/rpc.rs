@@ -0,0 +1,676 @@
+// JSON-RPC over HTTP, for scripting against a running node
+//
+// Every connection gets a thread that reads one HTTP request and hands the
+// call to the node loop along with a channel for the answer, the same way
+// the Network hands over peer messages, so the mempool, blockchain and
+// wallet are only ever touched from the node loop. Connections past
+// MAX_CONNECTIONS are turned away with a 503.
+//
+// calls are JSON-RPC 2.0 requests POSTed to any path
+//
+//   {"jsonrpc": "2.0", "id": 1, "method": "getblock", "params": {"id": 2}}
+//
+// methods:
+//   getblock           {"id": n} or {"hash": h}, the tip if neither is given
+//   getbalance *       confirmed, available and pending balance of our wallet
+//   getnewtransaction * {"to": [{"address": a, "amount": n}], "fee": n,
+//                      "msg": s}, a payment signed by our wallet but not sent,
+//                      its inputs stay pending until the next block if it
+//                      never is
+//   sendtransaction    {"transaction": t}, a transaction in the wire format,
+//                      added to the mempool and announced to our peers
+//   getmempool         the transactions waiting for a block
+//   getburnfee         the burn fee a block made now pays, and the fee it
+//                      falls from
+//
+// methods marked * use our wallet and need an "Authorization: Bearer <token>"
+// header carrying the token the server was started with, without a token
+// they are refused
+//
+// keys, hashes and signatures are base58, messages and wire format bytes
+// are hex and amounts are in nolan
+
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use base58::{FromBase58, ToBase58};
+use secp256k1::PublicKey;
+use serde_json::{json, Map, Value};
+
+use crate::{Amount, Block, Blockchain, BurnFee, Hop, Mempool, MempoolRejection, Network, PaymentError, Slip, Transaction, Wallet};
+
+// large enough for any transaction we would accept into the mempool
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+
+// longest request line or header we read
+const MAX_LINE: usize = 8192;
+
+// each connection holds a thread until it is answered
+const MAX_CONNECTIONS: usize = 32;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+// how long a connection waits on the node loop, which is busy while it
+// produces a block
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub enum RpcError {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams(&'static str),
+    BlockNotFound,
+    Payment(PaymentError),
+    Rejected(MempoolRejection),
+    // the node loop did not answer in time
+    Unavailable,
+    // a wallet method called without the token
+    Unauthorized,
+}
+
+impl RpcError {
+    pub fn return_code(&self) -> i64 {
+        return match self {
+            RpcError::ParseError => -32700,
+            RpcError::InvalidRequest => -32600,
+            RpcError::MethodNotFound => -32601,
+            RpcError::InvalidParams(_) => -32602,
+            RpcError::BlockNotFound => -32001,
+            RpcError::Payment(_) => -32002,
+            RpcError::Rejected(_) => -32003,
+            RpcError::Unavailable => -32000,
+            RpcError::Unauthorized => -32004,
+        };
+    }
+
+    pub fn return_message(&self) -> String {
+        return match self {
+            RpcError::ParseError => "parse error".to_string(),
+            RpcError::InvalidRequest => "invalid request".to_string(),
+            RpcError::MethodNotFound => "method not found".to_string(),
+            RpcError::InvalidParams(message) => format!("invalid params: {}", message),
+            RpcError::BlockNotFound => "block not found".to_string(),
+            RpcError::Payment(error) => format!("payment failed: {:?}", error),
+            RpcError::Rejected(reason) => format!("transaction rejected: {:?}", reason),
+            RpcError::Unavailable => "node unavailable".to_string(),
+            RpcError::Unauthorized => "unauthorized".to_string(),
+        };
+    }
+}
+
+// a call waiting on the node loop
+#[derive(Debug)]
+pub struct RpcRequest {
+    pub method: String,
+    pub params: Value,
+    reply: Sender<Result<Value, RpcError>>,
+}
+
+pub struct RpcServer {
+    requests: Receiver<RpcRequest>,
+    sender: Sender<RpcRequest>,
+    // needed by the wallet methods, which are refused if there is none
+    token: Option<String>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl RpcServer {
+    pub fn new(token: Option<String>) -> RpcServer {
+        let (sender, requests) = channel();
+        return RpcServer {
+            requests,
+            sender,
+            token: token.filter(|token| !token.is_empty()),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        };
+    }
+
+    // serve calls on addr, returns the address actually bound so that port
+    // 0 can be used in tests
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let sender = self.sender.clone();
+        let token = self.token.clone();
+        let shutdown = self.shutdown.clone();
+        let connections = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            connections.fetch_sub(1, Ordering::SeqCst);
+                            let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                            let _ = write_response(&mut stream, "503 Service Unavailable", None);
+                            continue;
+                        }
+
+                        let sender = sender.clone();
+                        let token = token.clone();
+                        let connections = connections.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = serve_connection(stream, &sender, &token) {
+                                println!("RPC CONNECTION FAILED -- {}", err);
+                            }
+                            connections.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(err) => println!("FAILED TO ACCEPT RPC CONNECTION -- {}", err),
+                }
+            }
+        });
+
+        return Ok(local_addr);
+    }
+
+    // answer every call made since the last one
+    pub fn process_requests(&self, mempool: &mut Mempool, blockchain: &Blockchain, wallet: &mut Wallet, burnfee: &BurnFee, network: &Network) {
+        while let Ok(request) = self.requests.try_recv() {
+            let result = handle_request(&request.method, &request.params, mempool, blockchain, wallet, burnfee, network);
+            let _ = request.reply.send(result);
+        }
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn handle_request(method: &str, params: &Value, mempool: &mut Mempool, blockchain: &Blockchain,
+                      wallet: &mut Wallet, burnfee: &BurnFee, network: &Network) -> Result<Value, RpcError> {
+    match method {
+        "getblock" => {
+            let block = match (params.get("hash"), params.get("id")) {
+                (Some(hash), _) => blockchain.get_block_by_hash(&read_base58(hash, "hash")?),
+                (None, Some(id)) => {
+                    let id = id.as_u64().filter(|id| *id <= u32::max_value() as u64).ok_or(RpcError::InvalidParams("id"))?;
+                    blockchain.get_block_by_id(id as u32)
+                },
+                (None, None) => blockchain.blocks.borrow().last().cloned(),
+            };
+            return block.map(|block| block.to_json()).ok_or(RpcError::BlockNotFound);
+        },
+        "getbalance" => {
+            return Ok(json!({
+                "address": wallet.return_base58(),
+                "balance": wallet.return_balance().return_nolan(),
+                "available": wallet.return_available_balance().return_nolan(),
+                "pending": wallet.return_pending_balance().return_nolan(),
+            }));
+        },
+        "getnewtransaction" => {
+            let recipients = params.get("to").and_then(|to| to.as_array()).ok_or(RpcError::InvalidParams("to"))?;
+            let mut payment = wallet.create_payment();
+            for recipient in recipients.iter() {
+                let address = read_publickey(recipient.get("address").unwrap_or(&Value::Null), "address")?;
+                let amount = read_amount(recipient.get("amount").unwrap_or(&Value::Null), "amount")?;
+                payment = payment.to(address, amount);
+            }
+            if let Some(fee) = params.get("fee") {
+                payment = payment.fee(read_amount(fee, "fee")?);
+            }
+            if let Some(msg) = params.get("msg") {
+                payment = payment.msg(msg.as_str().ok_or(RpcError::InvalidParams("msg"))?.as_bytes().to_vec());
+            }
+
+            let tx = payment.build().map_err(RpcError::Payment)?;
+            return Ok(json!({
+                "transaction": tx.to_json(),
+                "raw": hex::encode(tx.to_bytes()),
+            }));
+        },
+        "sendtransaction" => {
+            let tx_bytes = params.get("transaction")
+                .and_then(|encoded| encoded.as_str())
+                .and_then(|encoded| hex::decode(encoded).ok())
+                .ok_or(RpcError::InvalidParams("transaction"))?;
+            let tx = Transaction::from_bytes(&tx_bytes).map_err(|_| RpcError::InvalidParams("transaction"))?;
+
+            mempool.add_transaction(tx.clone(), blockchain).map_err(RpcError::Rejected)?;
+            wallet.mark_pending(&tx);
+            network.announce_transaction(&tx, None);
+            return Ok(json!({ "sig": tx.sig.serialize_compact().to_base58() }));
+        },
+        "getmempool" => {
+            let transactions: Vec<Value> = mempool.return_transactions().iter().map(|tx| tx.to_json()).collect();
+            return Ok(json!({
+                "size": mempool.return_size(),
+                "transactions": transactions,
+            }));
+        },
+        "getburnfee" => {
+            return Ok(json!({
+                "burnfee": burnfee.return_current_burnfee().return_nolan(),
+                "fee": burnfee.return_fee().return_nolan(),
+            }));
+        },
+        _ => return Err(RpcError::MethodNotFound),
+    }
+}
+
+impl Slip {
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "address": self.address.serialize().to_base58(),
+            "amount": self.amount.return_nolan(),
+            "block_id": self.block_id,
+            "transaction_id": self.transaction_id,
+            "id": self.id,
+            "block_hash": self.block_hash.to_base58(),
+        });
+    }
+}
+
+impl Hop {
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "from": self.from.serialize().to_base58(),
+            "to": self.to.serialize().to_base58(),
+            "sig": self.sig.serialize_compact().to_base58(),
+        });
+    }
+}
+
+impl Transaction {
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "sig": self.sig.serialize_compact().to_base58(),
+            "id": self.id,
+            "type": format!("{:?}", self.tx_type),
+            "timestamp": self.timestamp as u64,
+            "from": self.from.iter().map(|slip| slip.to_json()).collect::<Vec<Value>>(),
+            "to": self.to.iter().map(|slip| slip.to_json()).collect::<Vec<Value>>(),
+            "fees": self.return_fees().return_nolan(),
+            "msg": hex::encode(&self.msg),
+            "path": self.path.iter().map(|hop| hop.to_json()).collect::<Vec<Value>>(),
+        });
+    }
+}
+
+impl Block {
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "hash": self.return_block_hash().to_base58(),
+            "id": self.id,
+            "previous_hash": self.previous_hash.to_base58(),
+            "merkle_root": self.merkle_root.to_base58(),
+            "timestamp": self.timestamp as u64,
+            "creator": self.creator.serialize().to_base58(),
+            "difficulty": self.difficulty,
+            "paysplit": self.paysplit,
+            "treasury": self.treasury.return_nolan(),
+            "coinbase": self.coinbase.return_nolan(),
+            "reclaimed": self.reclaimed.return_nolan(),
+            "burnfee": self.burnfee.return_nolan(),
+            "paid_burnfee": self.paid_burnfee.return_nolan(),
+            "transactions": self.transactions.iter().map(|tx| tx.to_json()).collect::<Vec<Value>>(),
+        });
+    }
+}
+
+fn read_base58(value: &Value, name: &'static str) -> Result<Vec<u8>, RpcError> {
+    return value.as_str()
+        .and_then(|encoded| encoded.from_base58().ok())
+        .ok_or(RpcError::InvalidParams(name));
+}
+
+fn read_publickey(value: &Value, name: &'static str) -> Result<PublicKey, RpcError> {
+    return PublicKey::from_slice(&read_base58(value, name)?).map_err(|_| RpcError::InvalidParams(name));
+}
+
+fn read_amount(value: &Value, name: &'static str) -> Result<Amount, RpcError> {
+    return value.as_u64().map(Amount::from_nolan).ok_or(RpcError::InvalidParams(name));
+}
+
+fn is_wallet_method(method: &str) -> bool {
+    return method == "getbalance" || method == "getnewtransaction";
+}
+
+// compares every byte so the time taken does not give away how much of the
+// token was right
+fn is_authorized(authorization: Option<&str>, token: &Option<String>) -> bool {
+    let (authorization, token) = match (authorization, token) {
+        (Some(authorization), Some(token)) => (authorization.as_bytes(), format!("Bearer {}", token).into_bytes()),
+        _ => return false,
+    };
+    if authorization.len() != token.len() { return false; }
+    return authorization.iter().zip(token.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+}
+
+// the JSON-RPC answer to a request body, None for a notification
+fn return_response(body: &[u8], sender: &Sender<RpcRequest>, is_authorized: bool) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return Some(return_error_response(Value::Null, RpcError::ParseError)),
+    };
+
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(|method| method.as_str()) {
+        Some(method) => method.to_string(),
+        None => return Some(return_error_response(id.unwrap_or(Value::Null), RpcError::InvalidRequest)),
+    };
+    let params = match request.get("params") {
+        Some(Value::Object(params)) => Value::Object(params.clone()),
+        None | Some(Value::Null) => Value::Object(Map::new()),
+        Some(_) => return Some(return_error_response(id.unwrap_or(Value::Null), RpcError::InvalidParams("params"))),
+    };
+
+    let (reply, result) = channel();
+    let result = if is_wallet_method(&method) && !is_authorized {
+        Err(RpcError::Unauthorized)
+    } else {
+        match sender.send(RpcRequest { method, params, reply }) {
+            Ok(()) => result.recv_timeout(REPLY_TIMEOUT).unwrap_or(Err(RpcError::Unavailable)),
+            Err(_) => Err(RpcError::Unavailable),
+        }
+    };
+
+    let id = id?;
+    return match result {
+        Ok(result) => Some(json!({ "jsonrpc": "2.0", "id": id, "result": result })),
+        Err(error) => Some(return_error_response(id, error)),
+    };
+}
+
+fn return_error_response(id: Value, error: RpcError) -> Value {
+    return json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.return_code(), "message": error.return_message() },
+    });
+}
+
+// one request per connection, answered and closed
+fn serve_connection(stream: TcpStream, sender: &Sender<RpcRequest>, token: &Option<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let request_line = match read_line(&mut reader)? {
+        Some(request_line) => request_line,
+        None => return write_response(&mut writer, "414 URI Too Long", None),
+    };
+    let is_post = request_line.starts_with("POST ");
+
+    let mut content_length: Option<usize> = None;
+    let mut authorization: Option<String> = None;
+    let mut has_headers = false;
+    for _ in 0..MAX_HEADER_LINES {
+        let header = match read_line(&mut reader)? {
+            Some(header) => header,
+            None => return write_response(&mut writer, "431 Request Header Fields Too Large", None),
+        };
+        let header = header.trim();
+        if header.is_empty() {
+            has_headers = true;
+            break;
+        }
+
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = parts.next().and_then(|value| value.trim().parse().ok());
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = parts.next().map(|value| value.trim().to_string());
+        }
+    }
+
+    if !has_headers {
+        return write_response(&mut writer, "431 Request Header Fields Too Large", None);
+    }
+    if !is_post {
+        return write_response(&mut writer, "405 Method Not Allowed", None);
+    }
+    let content_length = match content_length {
+        Some(content_length) if content_length <= MAX_REQUEST_SIZE => content_length,
+        Some(_) => return write_response(&mut writer, "413 Payload Too Large", None),
+        None => return write_response(&mut writer, "411 Length Required", None),
+    };
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let is_authorized = is_authorized(authorization.as_deref(), token);
+    return match return_response(&body, sender, is_authorized) {
+        Some(response) => write_response(&mut writer, "200 OK", Some(&response)),
+        None => write_response(&mut writer, "204 No Content", None),
+    };
+}
+
+// a line of at most MAX_LINE bytes, an empty one at the end of the stream
+// and None if it runs on past that
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE as u64).read_line(&mut line)?;
+    if line.len() >= MAX_LINE && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    return Ok(Some(line));
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: Option<&Value>) -> io::Result<()> {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n", status, body.len());
+    if !body.is_empty() {
+        response.push_str("Content-Type: application/json\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes())?;
+    return stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+
+    use crate::AddBlockResult;
+
+    const TOKEN: &str = "correct horse";
+
+    struct TestNode {
+        rpc: RpcServer,
+        network: Network,
+        mempool: RefCell<Mempool>,
+        blockchain: Blockchain,
+        wallet: Wallet,
+        burnfee: BurnFee,
+        directory: PathBuf,
+    }
+
+    impl TestNode {
+        fn new() -> TestNode {
+            let directory = std::env::temp_dir().join(format!("saito-rpc-test-{}", rand::random::<u64>()));
+            let wallet = Wallet::new();
+            return TestNode {
+                rpc: RpcServer::new(Some(TOKEN.to_string())),
+                network: Network::new(wallet.return_publickey()),
+                mempool: Mempool::new(),
+                blockchain: Blockchain::new_with_directory(&directory),
+                wallet,
+                burnfee: BurnFee::new(),
+                directory,
+            };
+        }
+
+        // make an HTTP request to our server and answer it from this thread
+        fn request(&mut self, addr: SocketAddr, request: String) -> (String, Option<Value>) {
+            let (sender, response) = channel();
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                sender.send(response).unwrap();
+            });
+
+            let response = loop {
+                self.rpc.process_requests(&mut self.mempool.borrow_mut(), &self.blockchain, &mut self.wallet, &self.burnfee, &self.network);
+                if let Ok(response) = response.recv_timeout(Duration::from_millis(10)) { break response; }
+            };
+
+            let mut parts = response.splitn(2, "\r\n\r\n");
+            let status = parts.next().unwrap().lines().next().unwrap().to_string();
+            let body = parts.next().filter(|body| !body.is_empty()).map(|body| serde_json::from_str(body).unwrap());
+            return (status, body);
+        }
+
+        fn call(&mut self, addr: SocketAddr, method: &str, params: Value) -> Value {
+            return self.call_with_token(addr, Some(TOKEN), method, params);
+        }
+
+        fn call_with_token(&mut self, addr: SocketAddr, token: Option<&str>, method: &str, params: Value) -> Value {
+            let body = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params }).to_string();
+            let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+            let (status, response) = self.request(addr, format!("POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", authorization, body.len(), body));
+            assert_eq!(status, "HTTP/1.1 200 OK");
+
+            let response = response.unwrap();
+            assert_eq!(response["id"], 7);
+            return response;
+        }
+    }
+
+    impl Drop for TestNode {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn rpc_server_answers_chain_wallet_and_mempool_queries() {
+        let mut node = TestNode::new();
+        let addr = node.rpc.listen("127.0.0.1:0").unwrap();
+
+        let mut genesis_block = Block::new(Vec::new(), node.wallet.return_publickey());
+        genesis_block.set_merkle_root();
+        let result = node.mempool.borrow_mut().receive_block(genesis_block.clone(), &mut node.blockchain, &mut node.wallet);
+        assert_eq!(result, AddBlockResult::Extended);
+
+        let mut input = Slip::new(node.wallet.return_publickey());
+        input.amount = Amount::from_nolan(1000);
+        node.wallet.add_input(input.clone());
+        node.blockchain.insert_slip(input.return_index(), 1);
+
+        // the tip, by id and by hash
+        let tip = node.call(addr, "getblock", Value::Null)["result"].clone();
+        assert_eq!(tip, genesis_block.to_json());
+        assert_eq!(tip["creator"], node.wallet.return_base58());
+        assert_eq!(node.call(addr, "getblock", json!({ "id": 1 }))["result"], tip);
+        assert_eq!(node.call(addr, "getblock", json!({ "hash": tip["hash"] }))["result"], tip);
+        assert_eq!(node.call(addr, "getblock", json!({ "id": 2 }))["error"]["code"], -32001);
+        assert_eq!(node.call(addr, "getblock", json!({ "hash": "0OIl" }))["error"]["code"], -32602);
+
+        let burnfee = node.call(addr, "getburnfee", Value::Null)["result"].clone();
+        assert_eq!(burnfee["fee"], Amount::from_saito(10).return_nolan());
+
+        // the wallet is only ours to use with the token
+        let bob = Wallet::new();
+        let to_bob = json!({ "to": [{ "address": bob.return_base58(), "amount": 300 }] });
+        assert_eq!(node.call_with_token(addr, None, "getnewtransaction", to_bob.clone())["error"]["code"], -32004);
+        assert_eq!(node.call_with_token(addr, Some("correct horsf"), "getnewtransaction", to_bob)["error"]["code"], -32004);
+        assert_eq!(node.call_with_token(addr, Some("correct"), "getbalance", Value::Null)["error"]["code"], -32004);
+        assert_eq!(node.call_with_token(addr, None, "getblock", Value::Null)["result"], tip);
+        assert_eq!(node.wallet.return_available_balance(), Amount::from_nolan(1000));
+
+        // a payment is signed by the node, then sent back to it
+        let new_tx = node.call(addr, "getnewtransaction", json!({
+            "to": [{ "address": bob.return_base58(), "amount": 300 }],
+            "fee": 50,
+            "msg": "rent",
+        }))["result"].clone();
+        assert_eq!(new_tx["transaction"]["fees"], 50);
+        assert_eq!(new_tx["transaction"]["msg"], hex::encode(b"rent"));
+        assert_eq!(new_tx["transaction"]["to"][0]["address"], bob.return_base58());
+        assert_eq!(new_tx["transaction"]["to"][1]["amount"], 650);
+
+        let balance = node.call(addr, "getbalance", Value::Null)["result"].clone();
+        assert_eq!(balance, json!({ "address": node.wallet.return_base58(), "balance": 1000, "available": 0, "pending": 650 }));
+
+        let payment_error = node.call(addr, "getnewtransaction", json!({ "to": [{ "address": bob.return_base58(), "amount": 1 }] }));
+        assert_eq!(payment_error["error"]["code"], -32002);
+
+        let sent = node.call(addr, "sendtransaction", json!({ "transaction": new_tx["raw"] }))["result"].clone();
+        assert_eq!(sent["sig"], new_tx["transaction"]["sig"]);
+        let mempool = node.call(addr, "getmempool", Value::Null)["result"].clone();
+        assert_eq!(mempool["transactions"], json!([new_tx["transaction"]]));
+
+        // the same transaction again conflicts with itself
+        assert_eq!(node.call(addr, "sendtransaction", json!({ "transaction": new_tx["raw"] }))["error"]["code"], -32003);
+
+        // requests that never reach a method
+        assert_eq!(node.call(addr, "getpeers", Value::Null)["error"]["code"], -32601);
+        let (status, response) = node.request(addr, "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n{nope".to_string());
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(response.unwrap()["error"]["code"], -32700);
+        let (status, _) = node.request(addr, "GET / HTTP/1.1\r\n\r\n".to_string());
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[test]
+    fn rpc_server_turns_away_requests_with_oversized_lines() {
+        let mut node = TestNode::new();
+        let addr = node.rpc.listen("127.0.0.1:0").unwrap();
+
+        // each request is cut off where the server stops reading, so that
+        // it is read to the end before it is answered
+        let request_line = format!("POST /{}", "a".repeat(MAX_LINE - 6));
+        let (status, _) = node.request(addr, request_line);
+        assert_eq!(status, "HTTP/1.1 414 URI Too Long");
+
+        let header = format!("X-Padding: {}", "a".repeat(MAX_LINE - 11));
+        let (status, _) = node.request(addr, format!("POST / HTTP/1.1\r\n{}", header));
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+
+        let headers = "X-Padding: a\r\n".repeat(MAX_HEADER_LINES);
+        let (status, _) = node.request(addr, format!("POST / HTTP/1.1\r\n{}", headers));
+        assert_eq!(status, "HTTP/1.1 431 Request Header Fields Too Large");
+
+        // a line just inside the limit is read as usual
+        let header = format!("X-Padding: {}\r\n", "a".repeat(MAX_LINE - 13));
+        let (status, _) = node.request(addr, format!("GET / HTTP/1.1\r\n{}\r\n", header));
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[test]
+    fn rpc_server_turns_away_connections_past_the_limit() {
+        let mut node = TestNode::new();
+        let addr = node.rpc.listen("127.0.0.1:0").unwrap();
+
+        // connections that never send a request hold their threads
+        let idle: Vec<TcpStream> = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut response = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // and free them once they are answered
+        drop(idle);
+        let mut status = String::new();
+        for _ in 0..100 {
+            status = node.request(addr, "GET / HTTP/1.1\r\n\r\n".to_string()).0;
+            if status != "HTTP/1.1 503 Service Unavailable" { break; }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions, create_dir_all, read_dir, remove_file, rename};
+use std::io;
+use std::io::Read;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use base58::{FromBase58, ToBase58};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use digest::Digest;
+use sha2::Sha256;
+
+use crate::{Amount, Block};
+
+static INDEX_FILENAME: &str = "blocks.idx";
+// how many index records had their block files checked in full by the last
+// recovery pass
+static VERIFIED_FILENAME: &str = "blocks.verified";
+static QUARANTINE_DIRECTORY: &str = "quarantine";
+
+static BLOCK_FILE_MAGIC: &[u8; 4] = b"SAIT";
+const BLOCK_FILE_VERSION: u16 = 3;
+
+// magic (4) + version (2) + sha256 checksum of everything after it (32)
+const BLOCK_FILE_HEADER_SIZE: usize = 38;
+
+// id (4) + hash (32) + body offset (8) + work in nolan (8)
+const INDEX_RECORD_SIZE: usize = 52;
+
+// one record in the append-only index file
+//
+// every block file starts with a file header, then the block header and
+// finally the serialized transactions, and offset points at the first byte
+// of the transactions so the block header can be read on its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockStoreEntry {
+    pub id: u32,
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    pub work: Amount,
+}
+
+// what the recovery pass found wrong with the store, and the entries that
+// survived it in the order they should be connected
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    pub entries: Vec<BlockStoreEntry>,
+    pub quarantined: Vec<PathBuf>,
+    pub dropped: Vec<Vec<u8>>,
+    pub reindexed: Vec<Vec<u8>>,
+    // block files from before the store, named by timestamp, which are
+    // left where they are and never loaded
+    pub legacy: Vec<PathBuf>,
+    pub truncated_index: bool,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        return self.quarantined.is_empty()
+            && self.dropped.is_empty()
+            && self.reindexed.is_empty()
+            && !self.truncated_index;
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockStore {
+    directory: PathBuf,
+    entries: HashMap<Vec<u8>, BlockStoreEntry>,
+    ids: HashMap<u32, Vec<Vec<u8>>>,
+}
+
+impl BlockStore {
+    pub fn new(directory: &Path) -> BlockStore {
+        return BlockStore {
+            directory: directory.to_path_buf(),
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+        };
+    }
+
+    // read the index file and return its entries in the order the blocks
+    // were written, which always puts a parent before its children
+    pub fn load_index(&mut self) -> io::Result<Vec<BlockStoreEntry>> {
+        let mut loaded_entries: Vec<BlockStoreEntry> = Vec::new();
+        self.entries = HashMap::new();
+        self.ids = HashMap::new();
+
+        let index_path = self.directory.join(INDEX_FILENAME);
+        if !index_path.is_file() { return Ok(loaded_entries); }
+
+        let mut encoded = Vec::<u8>::new();
+        File::open(&index_path)?.read_to_end(&mut encoded)?;
+
+        // a record cut short by a crash while appending is ignored
+        for record in encoded.chunks(INDEX_RECORD_SIZE) {
+            if record.len() < INDEX_RECORD_SIZE { break; }
+
+            let mut reader = record;
+            let id = reader.read_u32::<BigEndian>()?;
+            let mut hash = vec![0; 32];
+            reader.read_exact(&mut hash)?;
+            let offset = reader.read_u64::<BigEndian>()?;
+            let work = Amount::from_nolan(reader.read_u64::<BigEndian>()?);
+
+            let entry = BlockStoreEntry { id, hash, offset, work };
+            self.insert_entry(entry.clone());
+            loaded_entries.push(entry);
+        }
+
+        return Ok(loaded_entries);
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> io::Result<BlockStoreEntry> {
+        create_dir_all(&self.directory)?;
+
+        let block_hash = block.return_block_hash();
+        let header_bytes = block.header_to_bytes();
+        let body_bytes = block.transactions_to_bytes();
+
+        let mut payload: Vec<u8> = Vec::with_capacity(header_bytes.len() + body_bytes.len());
+        payload.extend(&header_bytes);
+        payload.extend(&body_bytes);
+
+        let mut file_bytes: Vec<u8> = Vec::with_capacity(BLOCK_FILE_HEADER_SIZE + payload.len());
+        file_bytes.extend(BLOCK_FILE_MAGIC);
+        file_bytes.write_u16::<BigEndian>(BLOCK_FILE_VERSION)?;
+        file_bytes.extend(return_checksum(&payload));
+        file_bytes.extend(&payload);
+
+        self.write_atomically(&self.return_block_path(&block_hash), &file_bytes)?;
+
+        let entry = BlockStoreEntry {
+            id: block.id,
+            hash: block_hash,
+            offset: (BLOCK_FILE_HEADER_SIZE + header_bytes.len()) as u64,
+            work: block.return_work(),
+        };
+
+        // the block file is complete before it is indexed, so a crash here
+        // leaves at worst an unindexed file for the recovery pass to pick up
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(INDEX_FILENAME))?;
+        index_file.write_all(&return_index_record(&entry)[..])?;
+        index_file.sync_all()?;
+
+        self.insert_entry(entry.clone());
+        return Ok(entry);
+    }
+
+    // read only the header of a block, leaving its transactions empty
+    //
+    // the checksum covers the whole file so it is only checked when the
+    // full block is read
+    pub fn read_header(&self, block_hash: &Vec<u8>) -> io::Result<Block> {
+        let entry = self.return_entry(block_hash)?;
+
+        let mut file_bytes = vec![0; entry.offset as usize];
+        File::open(self.return_block_path(block_hash))?.read_exact(&mut file_bytes)?;
+        check_file_header(&file_bytes)?;
+
+        return Block::header_from_bytes(&file_bytes[BLOCK_FILE_HEADER_SIZE..]);
+    }
+
+    pub fn read_block(&self, block_hash: &Vec<u8>) -> io::Result<Block> {
+        let entry = self.return_entry(block_hash)?;
+
+        let payload = read_payload(&self.return_block_path(block_hash))?;
+        let body_offset = entry.offset as usize - BLOCK_FILE_HEADER_SIZE;
+        if body_offset > payload.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block file truncated"));
+        }
+
+        let mut block = Block::header_from_bytes(&payload[..body_offset])?;
+        block.transactions = Block::transactions_from_bytes(&payload[body_offset..])?;
+
+        return Ok(block);
+    }
+
+    pub fn contains(&self, block_hash: &Vec<u8>) -> bool {
+        return self.entries.contains_key(block_hash);
+    }
+
+    pub fn return_hashes_by_id(&self, id: u32) -> Vec<Vec<u8>> {
+        return match self.ids.get(&id) {
+            Some(hashes) => hashes.clone(),
+            None => Vec::new(),
+        };
+    }
+
+    // check the block files written since the last recovery against their
+    // checksums, move corrupt files out of the way and rebuild the index from
+    // the blocks that are still good. Files checked before only have their
+    // headers read, so startup does not grow with the length of the chain
+    //
+    // a block is only kept if its parent was kept, so the chain is rebuilt
+    // up to the last good block on each fork. Only a genesis block starts a
+    // chain, so nothing survives a corrupt genesis block
+    pub fn recover(&mut self) -> io::Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+        if !self.directory.is_dir() { return Ok(report); }
+
+        let indexed_entries = self.load_index()?;
+        let verified_count = self.return_verified_count();
+
+        let index_path = self.directory.join(INDEX_FILENAME);
+        if index_path.is_file() {
+            report.truncated_index = index_path.metadata()?.len() as usize % INDEX_RECORD_SIZE != 0;
+        }
+
+        // leftovers from writes that never reached the rename
+        let mut unindexed_paths: Vec<PathBuf> = Vec::new();
+        for dir_entry in read_dir(&self.directory)? {
+            let path = dir_entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("tmp") => remove_file(&path)?,
+                Some("sai") => {
+                    match return_hash_from_path(&path) {
+                        Some(block_hash) => {
+                            if !self.contains(&block_hash) { unindexed_paths.push(path); }
+                        },
+                        None => report.legacy.push(path),
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        // only the block headers are deserialized, the checksum is enough
+        // to trust the transactions
+        let mut good_hashes: HashSet<Vec<u8>> = HashSet::new();
+        for (i, entry) in indexed_entries.iter().enumerate() {
+            let path = self.return_block_path(&entry.hash);
+            let header = if i < verified_count {
+                self.read_header(&entry.hash)
+            } else {
+                read_payload(&path).and_then(|payload| deserialize_header(&payload))
+            };
+            match header {
+                Ok(block) => {
+                    if block.return_block_hash() != entry.hash {
+                        report.quarantined.push(self.quarantine(&path)?);
+                    } else if !block.previous_hash.is_empty() && !good_hashes.contains(&block.previous_hash) {
+                        report.dropped.push(entry.hash.clone());
+                        report.quarantined.push(self.quarantine(&path)?);
+                    } else {
+                        good_hashes.insert(entry.hash.clone());
+                        report.entries.push(entry.clone());
+                    }
+                },
+                Err(_) => {
+                    if path.is_file() {
+                        report.quarantined.push(self.quarantine(&path)?);
+                    }
+                    report.dropped.push(entry.hash.clone());
+                },
+            }
+        }
+
+        // a crash between writing a block file and indexing it leaves a
+        // complete block on disk that can simply be indexed again. The work
+        // stored in the index is calculated from the transactions so
+        // reindexing a block needs its body
+        let mut unindexed_blocks: Vec<(PathBuf, Block)> = Vec::new();
+        for path in unindexed_paths.into_iter() {
+            match read_payload(&path).and_then(|payload| Block::from_bytes(&payload)) {
+                Ok(block) => unindexed_blocks.push((path, block)),
+                Err(_) => report.quarantined.push(self.quarantine(&path)?),
+            }
+        }
+
+        // parents have lower ids, so they are indexed before their children
+        unindexed_blocks.sort_by_key(|(_, block)| block.id);
+        for (path, block) in unindexed_blocks.iter() {
+            let block_hash = block.return_block_hash();
+            if Some(block_hash.clone()) == return_hash_from_path(path)
+                && (block.previous_hash.is_empty() || good_hashes.contains(&block.previous_hash)) {
+                let header_size = block.header_to_bytes().len();
+                report.entries.push(BlockStoreEntry {
+                    id: block.id,
+                    hash: block_hash.clone(),
+                    offset: (BLOCK_FILE_HEADER_SIZE + header_size) as u64,
+                    work: block.return_work(),
+                });
+                report.reindexed.push(block_hash.clone());
+                good_hashes.insert(block_hash);
+            } else {
+                report.quarantined.push(self.quarantine(path)?);
+            }
+        }
+
+        if !report.is_clean() {
+            let mut index_bytes: Vec<u8> = Vec::new();
+            for entry in report.entries.iter() {
+                index_bytes.extend(return_index_record(entry));
+            }
+            self.write_atomically(&index_path, &index_bytes)?;
+
+            self.entries = HashMap::new();
+            self.ids = HashMap::new();
+            for entry in report.entries.iter() {
+                self.insert_entry(entry.clone());
+            }
+        }
+
+        let mut verified_bytes: Vec<u8> = Vec::new();
+        verified_bytes.write_u64::<BigEndian>(report.entries.len() as u64)?;
+        self.write_atomically(&self.directory.join(VERIFIED_FILENAME), &verified_bytes)?;
+
+        return Ok(report);
+    }
+
+    // a missing or unreadable count checks every block file
+    fn return_verified_count(&self) -> usize {
+        let mut encoded = Vec::<u8>::new();
+        let count = File::open(self.directory.join(VERIFIED_FILENAME))
+            .and_then(|mut f| f.read_to_end(&mut encoded))
+            .and_then(|_| (&encoded[..]).read_u64::<BigEndian>());
+        return count.map(|count| count as usize).unwrap_or(0);
+    }
+
+    // write to a temporary file, flush it to disk and rename it into place
+    // so the file at path is always either the old or the new contents
+    fn write_atomically(&self, path: &Path, bytes: &Vec<u8>) -> io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+
+        let mut f = File::create(&temp_path)?;
+        f.write_all(&bytes[..])?;
+        f.sync_all()?;
+
+        rename(&temp_path, path)?;
+        File::open(&self.directory)?.sync_all()?;
+
+        return Ok(());
+    }
+
+    fn quarantine(&self, path: &Path) -> io::Result<PathBuf> {
+        let quarantine_directory = self.directory.join(QUARANTINE_DIRECTORY);
+        create_dir_all(&quarantine_directory)?;
+
+        let quarantine_path = quarantine_directory.join(path.file_name().unwrap());
+        rename(path, &quarantine_path)?;
+        return Ok(quarantine_path);
+    }
+
+    fn return_entry(&self, block_hash: &Vec<u8>) -> io::Result<&BlockStoreEntry> {
+        return self.entries.get(block_hash)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "block not in index"));
+    }
+
+    fn return_block_path(&self, block_hash: &Vec<u8>) -> PathBuf {
+        let mut filename = block_hash.to_base58();
+        filename.push_str(&".sai".to_string());
+        return self.directory.join(filename);
+    }
+
+    fn insert_entry(&mut self, entry: BlockStoreEntry) {
+        self.ids.entry(entry.id).or_insert(Vec::new()).push(entry.hash.clone());
+        self.entries.insert(entry.hash.clone(), entry);
+    }
+}
+
+fn return_checksum(payload: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    return hasher.result().to_vec();
+}
+
+fn return_index_record(entry: &BlockStoreEntry) -> Vec<u8> {
+    let mut record: Vec<u8> = Vec::with_capacity(INDEX_RECORD_SIZE);
+    record.write_u32::<BigEndian>(entry.id).unwrap();
+    record.extend(&entry.hash);
+    record.write_u64::<BigEndian>(entry.offset).unwrap();
+    record.write_u64::<BigEndian>(entry.work.return_nolan()).unwrap();
+    return record;
+}
+
+fn return_hash_from_path(path: &Path) -> Option<Vec<u8>> {
+    return path.file_stem()
+        .and_then(|file_stem| file_stem.to_str())
+        .and_then(|file_stem| file_stem.from_base58().ok())
+        .filter(|block_hash| block_hash.len() == 32);
+}
+
+fn check_file_header(file_bytes: &[u8]) -> io::Result<()> {
+    if file_bytes.len() < BLOCK_FILE_HEADER_SIZE || &file_bytes[0..4] != BLOCK_FILE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a block file"));
+    }
+
+    let mut version_bytes = &file_bytes[4..6];
+    if version_bytes.read_u16::<BigEndian>()? != BLOCK_FILE_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported block file version"));
+    }
+
+    return Ok(());
+}
+
+// read a block file and verify it against its checksum, returning
+// everything after the file header
+fn read_payload(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file_bytes = Vec::<u8>::new();
+    File::open(path)?.read_to_end(&mut file_bytes)?;
+    check_file_header(&file_bytes)?;
+
+    let payload = file_bytes.split_off(BLOCK_FILE_HEADER_SIZE);
+    if return_checksum(&payload)[..] != file_bytes[6..BLOCK_FILE_HEADER_SIZE] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "block file checksum mismatch"));
+    }
+
+    return Ok(payload);
+}
+
+// reading stops at the start of the transactions
+fn deserialize_header(payload: &[u8]) -> io::Result<Block> {
+    return Block::header_from_prefix(payload).map(|(block, _)| block);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_keys, Transaction};
+
+    #[test]
+    fn block_store_reloads_index_and_reads_headers() {
+        let directory = std::env::temp_dir().join(format!("saito-test-{}", rand::random::<u32>()));
+        let (_, publickey) = generate_keys();
+
+        let mut block = Block::new(Vec::new(), publickey);
+        block.transactions.push(Transaction::new(crate::TransactionType::Base));
+
+        let mut store = BlockStore::new(&directory);
+        let entry = store.write_block(&block).unwrap();
+
+        let mut reloaded_store = BlockStore::new(&directory);
+        assert_eq!(reloaded_store.load_index().unwrap(), vec![entry]);
+        assert_eq!(reloaded_store.return_hashes_by_id(1), vec![block.return_block_hash()]);
+        assert_eq!(reloaded_store.read_header(&block.return_block_hash()).unwrap().return_header(), block.return_header());
+        assert_eq!(reloaded_store.read_block(&block.return_block_hash()).unwrap(), block);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn block_store_recovery_quarantines_corrupt_blocks() {
+        let directory = std::env::temp_dir().join(format!("saito-test-{}", rand::random::<u32>()));
+        let (_, publickey) = generate_keys();
+
+        let genesis_block = Block::new(Vec::new(), publickey);
+        let mut block = Block::new(genesis_block.return_block_hash(), publickey);
+        block.id = 2;
+
+        let mut store = BlockStore::new(&directory);
+        let genesis_entry = store.write_block(&genesis_block).unwrap();
+        store.write_block(&block).unwrap();
+
+        // flip a byte in the second block and leave a half written file
+        let block_path = store.return_block_path(&block.return_block_hash());
+        let mut file_bytes = std::fs::read(&block_path).unwrap();
+        let last_byte = file_bytes.len() - 1;
+        file_bytes[last_byte] ^= 0xff;
+        std::fs::write(&block_path, file_bytes).unwrap();
+        std::fs::write(directory.join("unfinished.tmp"), vec![0; 10]).unwrap();
+        std::fs::write(directory.join("1565000000000.sai"), vec![0; 10]).unwrap();
+
+        let mut recovered_store = BlockStore::new(&directory);
+        let report = recovered_store.recover().unwrap();
+        assert_eq!(report.entries, vec![genesis_entry.clone()]);
+        assert_eq!(report.dropped, vec![block.return_block_hash()]);
+        assert_eq!(report.quarantined.len(), 1);
+        assert!(!directory.join("unfinished.tmp").exists());
+
+        // block files named by timestamp are reported and left alone
+        assert_eq!(report.legacy, vec![directory.join("1565000000000.sai")]);
+        assert!(directory.join("1565000000000.sai").exists());
+
+        let mut reloaded_store = BlockStore::new(&directory);
+        assert_eq!(reloaded_store.recover().unwrap().is_clean(), true);
+        assert_eq!(reloaded_store.load_index().unwrap(), vec![genesis_entry]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn block_store_recovery_only_checks_blocks_written_since_the_last_pass() {
+        let directory = std::env::temp_dir().join(format!("saito-test-{}", rand::random::<u32>()));
+        let (_, publickey) = generate_keys();
+
+        let genesis_block = Block::new(Vec::new(), publickey);
+        let mut block = Block::new(genesis_block.return_block_hash(), publickey);
+        block.id = 2;
+        block.transactions.push(Transaction::new(crate::TransactionType::Base));
+
+        let mut store = BlockStore::new(&directory);
+        let genesis_entry = store.write_block(&genesis_block).unwrap();
+        let entry = store.write_block(&block).unwrap();
+        assert!(BlockStore::new(&directory).recover().unwrap().is_clean());
+
+        // a body corrupted after it was checked is only found when it is read
+        let block_path = store.return_block_path(&block.return_block_hash());
+        let mut file_bytes = std::fs::read(&block_path).unwrap();
+        let last_byte = file_bytes.len() - 1;
+        file_bytes[last_byte] ^= 0xff;
+        std::fs::write(&block_path, file_bytes).unwrap();
+
+        let mut recovered_store = BlockStore::new(&directory);
+        let report = recovered_store.recover().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.entries, vec![genesis_entry.clone(), entry.clone()]);
+        assert!(recovered_store.read_block(&block.return_block_hash()).is_err());
+
+        // a block written since is checked in full
+        let mut child_block = Block::new(block.return_block_hash(), publickey);
+        child_block.id = 3;
+        child_block.transactions.push(Transaction::new(crate::TransactionType::Base));
+        recovered_store.write_block(&child_block).unwrap();
+        let child_path = store.return_block_path(&child_block.return_block_hash());
+        let mut file_bytes = std::fs::read(&child_path).unwrap();
+        let last_byte = file_bytes.len() - 1;
+        file_bytes[last_byte] ^= 0xff;
+        std::fs::write(&child_path, file_bytes).unwrap();
+
+        let report = BlockStore::new(&directory).recover().unwrap();
+        assert_eq!(report.entries, vec![genesis_entry, entry]);
+        assert_eq!(report.dropped, vec![child_block.return_block_hash()]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn block_store_recovery_reindexes_parents_first_and_only_from_genesis() {
+        let directory = std::env::temp_dir().join(format!("saito-test-{}", rand::random::<u32>()));
+        let (_, publickey) = generate_keys();
+
+        let mut chain = vec![Block::new(Vec::new(), publickey)];
+        for id in 2..6 {
+            let mut block = Block::new(chain.last().unwrap().return_block_hash(), publickey);
+            block.id = id;
+            chain.push(block);
+        }
+
+        let mut store = BlockStore::new(&directory);
+        let entries: Vec<BlockStoreEntry> = chain.iter().map(|block| store.write_block(block).unwrap()).collect();
+
+        // every file is unindexed, whatever order the directory lists them in
+        std::fs::remove_file(directory.join(INDEX_FILENAME)).unwrap();
+        let report = BlockStore::new(&directory).recover().unwrap();
+        assert_eq!(report.entries, entries);
+        assert_eq!(report.reindexed.len(), chain.len());
+
+        // without its genesis block no block starts a chain, its header is
+        // read even though the file was checked by the last pass
+        let genesis_path = store.return_block_path(&chain[0].return_block_hash());
+        let mut file_bytes = std::fs::read(&genesis_path).unwrap();
+        file_bytes[BLOCK_FILE_HEADER_SIZE + 8] ^= 0xff;
+        std::fs::write(&genesis_path, file_bytes).unwrap();
+
+        let report = BlockStore::new(&directory).recover().unwrap();
+        assert!(report.entries.is_empty());
+        assert_eq!(report.quarantined.len(), chain.len());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
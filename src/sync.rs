@@ -0,0 +1,382 @@
+// Catching up with a peer that is ahead of us
+//
+// We ask one peer for the headers that follow our longest chain, check that
+// they form a chain on top of a block we have, and then download the bodies
+// in batches spread over the peers whose tip reaches them. Bodies are connected
+// in header order through the mempool, so each block is validated against
+// its parent exactly as if it had been announced.
+//
+// Nothing about a sync survives its peer. Every connected block is already
+// in the block store, so a sync that is interrupted, by a disconnect or by
+// the node stopping, starts again from whatever our tip is by then.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::network::{PeerId, PeerMessage};
+use crate::{AddBlockResult, Block, Blockchain, Mempool, Wallet};
+
+// most headers a peer sends in answer to one request
+pub const MAX_HEADERS: u32 = 2000;
+
+// bodies asked for in one request, and requests outstanding per peer
+pub const SYNC_BATCH_SIZE: usize = 16;
+const MAX_BATCHES_PER_PEER: usize = 4;
+
+// how far past the next block to connect we download, which bounds the
+// bodies held in memory
+const SYNC_WINDOW: usize = 512;
+
+// ask for the next headers once fewer than this are left to download
+const HEADERS_LOW_WATER: usize = SYNC_WINDOW;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// times a body is asked for before we give up on the sync
+const MAX_BODY_ATTEMPTS: u32 = 3;
+
+pub struct ChainSync {
+    peer: Option<PeerId>,
+    target_id: u32,
+
+    // verified headers whose bodies have not been connected, in chain order
+    headers: VecDeque<Block>,
+    // the end of the header chain, the next headers have to follow it
+    last_header: Option<Block>,
+    headers_requested: Option<Instant>,
+    more_headers: bool,
+
+    requested: HashMap<Vec<u8>, (PeerId, Instant)>,
+    // times a body has been asked for, and the peer asked last
+    attempts: HashMap<Vec<u8>, (u32, PeerId)>,
+    bodies: HashMap<Vec<u8>, Block>,
+
+    // where the next batch starts looking for a peer
+    next_peer: usize,
+}
+
+impl ChainSync {
+    pub fn new() -> ChainSync {
+        return ChainSync {
+            peer: None,
+            target_id: 0,
+            headers: VecDeque::new(),
+            last_header: None,
+            headers_requested: None,
+            more_headers: false,
+            requested: HashMap::new(),
+            attempts: HashMap::new(),
+            bodies: HashMap::new(),
+            next_peer: 0,
+        };
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        return self.peer.is_some();
+    }
+
+    pub fn return_peer(&self) -> Option<PeerId> {
+        return self.peer;
+    }
+
+    pub fn return_target_id(&self) -> u32 {
+        return self.target_id;
+    }
+
+    // start syncing from peer, returns the request for the first headers
+    pub fn start(&mut self, peer: PeerId, target_id: u32, blockchain: &Blockchain) -> PeerMessage {
+        self.abort();
+        self.peer = Some(peer);
+        self.target_id = target_id;
+        self.more_headers = true;
+        self.headers_requested = Some(Instant::now());
+        return PeerMessage::RequestHeaders { locator: return_block_locator(blockchain), max: MAX_HEADERS };
+    }
+
+    pub fn abort(&mut self) {
+        *self = ChainSync::new();
+    }
+
+    // check that the headers continue the header chain, or start on a
+    // block we already have, and queue them for download
+    pub fn receive_headers(&mut self, peer: PeerId, headers: Vec<Block>, blockchain: &Blockchain) -> bool {
+        if self.peer != Some(peer) || self.headers_requested.is_none() { return false; }
+        self.headers_requested = None;
+        self.more_headers = headers.len() as u32 >= MAX_HEADERS;
+
+        let mut previous_block: Option<Block> = match (&self.last_header, headers.first()) {
+            (Some(last_header), _) => Some(last_header.clone()),
+            (None, Some(first_header)) => blockchain.get_block_by_hash(&first_header.previous_hash),
+            (None, None) => None,
+        };
+
+        for header in headers.into_iter() {
+            match &previous_block {
+                Some(previous_block) => {
+                    if !header.validate_header(previous_block) { return false; }
+                },
+                // only a node without a chain takes a genesis block from a peer
+                None => {
+                    if !blockchain.index.is_empty() || !header.previous_hash.is_empty() { return false; }
+                },
+            }
+
+            // a peer on our chain starts after the last block we have
+            if !blockchain.index.contains_key(&header.return_block_hash()) {
+                self.headers.push_back(header.clone());
+            }
+            previous_block = Some(header);
+        }
+
+        self.last_header = previous_block;
+        return true;
+    }
+
+    // the header request that keeps the download window full, if one is due
+    pub fn request_headers(&mut self) -> Option<(PeerId, PeerMessage)> {
+        let peer = self.peer?;
+        if !self.more_headers || self.headers_requested.is_some() || self.headers.len() >= HEADERS_LOW_WATER {
+            return None;
+        }
+
+        let last_header = self.last_header.as_ref()?;
+        self.headers_requested = Some(Instant::now());
+        return Some((peer, PeerMessage::RequestHeaders { locator: vec![last_header.return_block_hash()], max: MAX_HEADERS }));
+    }
+
+    // batches of bodies to ask for, spread over the peers we have with the
+    // tip each of them announced. A batch only goes to a peer whose tip
+    // reaches its last block, or to the peer we are syncing from
+    pub fn request_bodies(&mut self, peers: &Vec<(PeerId, u32)>) -> Vec<(PeerId, PeerMessage)> {
+        let mut requests: Vec<(PeerId, PeerMessage)> = Vec::new();
+        if peers.is_empty() { return requests; }
+
+        let mut in_flight: HashMap<PeerId, usize> = HashMap::new();
+        for (peer, _) in self.requested.values() {
+            *in_flight.entry(*peer).or_insert(0) += 1;
+        }
+
+        let wanted: Vec<(Vec<u8>, u32)> = self.headers.iter()
+            .take(SYNC_WINDOW)
+            .map(|header| (header.return_block_hash(), header.id))
+            .filter(|(hash, _)| !self.bodies.contains_key(hash) && !self.requested.contains_key(hash))
+            .collect();
+
+        let now = Instant::now();
+        for batch in wanted.chunks(SYNC_BATCH_SIZE) {
+            let last_id = batch[batch.len() - 1].1;
+
+            // the next peer in turn that has the batch and room for it, and
+            // that is not the one a batch asked for again went to last time
+            // unless nobody else can take it
+            let candidates: Vec<usize> = (0..peers.len())
+                .map(|offset| (self.next_peer + offset) % peers.len())
+                .filter(|index| {
+                    let (peer, tip_id) = peers[*index];
+                    (tip_id >= last_id || Some(peer) == self.peer)
+                        && in_flight.get(&peer).cloned().unwrap_or(0) + batch.len() <= SYNC_BATCH_SIZE * MAX_BATCHES_PER_PEER
+                })
+                .collect();
+            let previous_peer = self.attempts.get(&batch[0].0).map(|(_, peer)| *peer);
+            let index = match candidates.iter().find(|index| Some(peers[**index].0) != previous_peer).or(candidates.first()) {
+                Some(index) => *index,
+                None => break,
+            };
+            let peer = peers[index].0;
+            self.next_peer = (index + 1) % peers.len();
+
+            *in_flight.entry(peer).or_insert(0) += batch.len();
+            for (hash, _) in batch.iter() {
+                self.requested.insert(hash.clone(), (peer, now));
+                let attempts = self.attempts.entry(hash.clone()).or_insert((0, peer));
+                *attempts = (attempts.0 + 1, peer);
+            }
+            requests.push((peer, PeerMessage::RequestBlocks(batch.iter().map(|(hash, _)| hash.clone()).collect())));
+        }
+
+        return requests;
+    }
+
+    pub fn is_expected(&self, block_hash: &Vec<u8>) -> bool {
+        return self.requested.contains_key(block_hash);
+    }
+
+    pub fn receive_body(&mut self, block: Block) {
+        let block_hash = block.return_block_hash();
+        if self.requested.remove(&block_hash).is_some() {
+            self.attempts.remove(&block_hash);
+            self.bodies.insert(block_hash, block);
+        }
+    }
+
+    // forget body requests that timed out, or that went to a peer that has
+    // gone, so that they are asked for again. returns false once a body has
+    // been asked for MAX_BODY_ATTEMPTS times without arriving
+    pub fn expire_body_requests(&mut self, connected_peers: &Vec<PeerId>) -> bool {
+        let now = Instant::now();
+        let attempts = &self.attempts;
+        let mut exhausted = false;
+        self.requested.retain(|hash, (peer, requested_at)| {
+            let keep = connected_peers.contains(peer) && now.duration_since(*requested_at) < REQUEST_TIMEOUT;
+            if !keep && attempts.get(hash).map_or(0, |(count, _)| *count) >= MAX_BODY_ATTEMPTS {
+                exhausted = true;
+            }
+            return keep;
+        });
+        return !exhausted;
+    }
+
+    // returns false if the headers we are waiting on are not coming
+    pub fn expire_header_request(&self, connected_peers: &Vec<PeerId>) -> bool {
+        let now = Instant::now();
+        match (self.peer, self.headers_requested) {
+            (Some(peer), _) if !connected_peers.contains(&peer) => return false,
+            (_, Some(requested_at)) if now.duration_since(requested_at) >= REQUEST_TIMEOUT => return false,
+            _ => return true,
+        }
+    }
+
+    // connect the bodies that are next in line, returns how many were
+    // connected or None if one of them turned out to be invalid
+    pub fn connect_bodies(&mut self, mempool: &mut Mempool, blockchain: &mut Blockchain, wallet: &mut Wallet) -> Option<u32> {
+        let mut connected = 0;
+        while let Some(header) = self.headers.front() {
+            let block = match self.bodies.remove(&header.return_block_hash()) {
+                Some(block) => block,
+                None => break,
+            };
+            self.headers.pop_front();
+
+            match mempool.receive_block(block, blockchain, wallet) {
+                AddBlockResult::Extended | AddBlockResult::Reorganized(_) | AddBlockResult::Fork | AddBlockResult::Duplicate => {
+                    connected += 1;
+                },
+                _ => return None,
+            }
+        }
+        return Some(connected);
+    }
+
+    // every header has been downloaded and the peer has nothing more
+    pub fn is_complete(&self) -> bool {
+        return self.is_syncing() && !self.more_headers && self.headers_requested.is_none() && self.headers.is_empty();
+    }
+}
+
+// hashes of our longest chain for a peer to find where its chain leaves
+// ours, the tip and its parents at exponentially growing distances
+pub fn return_block_locator(blockchain: &Blockchain) -> Vec<Vec<u8>> {
+    let longest_chain = blockchain.return_longest_chain();
+    let mut locator: Vec<Vec<u8>> = Vec::new();
+    let mut step = 1;
+    let mut position = longest_chain.len();
+
+    while position > 0 {
+        position -= 1;
+        locator.push(longest_chain[position].clone());
+        if locator.len() >= 10 { step *= 2; }
+        if position < step {
+            if position > 0 { locator.push(longest_chain[0].clone()); }
+            break;
+        }
+        position = position + 1 - step;
+    }
+    return locator;
+}
+
+// the headers on our longest chain after the first locator hash we have,
+// or from genesis if we have none of them
+pub fn return_headers_after(blockchain: &Blockchain, locator: &Vec<Vec<u8>>, max: u32) -> Vec<Block> {
+    let longest_chain = blockchain.return_longest_chain();
+    let genesis_id = match longest_chain.first() {
+        Some(genesis_hash) => blockchain.index[genesis_hash].id,
+        None => return Vec::new(),
+    };
+
+    let mut start = 0;
+    for block_hash in locator.iter() {
+        match blockchain.index.get(block_hash) {
+            Some(block_index) if block_index.lc => {
+                start = (block_index.id - genesis_id) as usize + 1;
+                break;
+            },
+            _ => {},
+        }
+    }
+
+    // headers are read from the store, stopping short of one we can not
+    // read so the peer still gets a chain
+    let mut headers: Vec<Block> = Vec::new();
+    for block_hash in longest_chain.iter().skip(start).take(max.min(MAX_HEADERS) as usize) {
+        match blockchain.get_header_by_hash(block_hash) {
+            Some(header) => headers.push(header),
+            None => break,
+        }
+    }
+    return headers;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a sync from peer 1 with headers for blocks 1 to count, whose bodies
+    // are all still to come
+    fn create_sync(count: u32) -> ChainSync {
+        let publickey = Wallet::new().return_publickey();
+        let mut sync = ChainSync::new();
+        sync.peer = Some(1);
+        sync.target_id = count;
+        for id in 1..=count {
+            let mut header = Block::new(id.to_be_bytes().to_vec(), publickey);
+            header.id = id;
+            sync.headers.push_back(header);
+        }
+        return sync;
+    }
+
+    fn expire_all_requests(sync: &mut ChainSync) {
+        let requested_at = Instant::now().checked_sub(REQUEST_TIMEOUT).unwrap();
+        for (_, request) in sync.requested.iter_mut() {
+            request.1 = requested_at;
+        }
+    }
+
+    #[test]
+    fn bodies_are_asked_of_peers_that_have_them_and_of_another_peer_on_retry() {
+        let batch_size = SYNC_BATCH_SIZE as u32;
+        let mut sync = create_sync(batch_size * 2);
+        let first_batch: Vec<Vec<u8>> = sync.headers.iter().take(SYNC_BATCH_SIZE).map(|header| header.return_block_hash()).collect();
+
+        // peer 2 is short of the second batch and peer 3 has none of it,
+        // the peer we sync from is asked whatever it announced
+        let peers = vec![(1, 0), (2, batch_size), (3, batch_size - 1)];
+        let requests = sync.request_bodies(&peers);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], (1, PeerMessage::RequestBlocks(first_batch.clone())));
+        assert_eq!(requests[1].0, 1);
+
+        // the first batch goes to the short peer that has it when it is
+        // asked for again, and back to the sync peer after that
+        expire_all_requests(&mut sync);
+        assert!(sync.expire_body_requests(&vec![1, 2, 3]));
+        let requests = sync.request_bodies(&peers);
+        assert_eq!(requests[0], (2, PeerMessage::RequestBlocks(first_batch.clone())));
+        assert!(requests.iter().all(|(peer, _)| *peer != 3));
+
+        expire_all_requests(&mut sync);
+        assert!(sync.expire_body_requests(&vec![1, 2, 3]));
+        let requests = sync.request_bodies(&peers);
+        assert_eq!(requests[0], (1, PeerMessage::RequestBlocks(first_batch.clone())));
+
+        // a body that has arrived is not asked for again
+        let body = sync.headers.front().unwrap().clone();
+        sync.receive_body(body);
+        assert!(!sync.attempts.contains_key(&first_batch[0]));
+
+        // and the sync gives up on the rest once they have been asked for
+        // as often as we allow
+        expire_all_requests(&mut sync);
+        assert!(!sync.expire_body_requests(&vec![1, 2, 3]));
+    }
+}
@@ -0,0 +1,597 @@
+// Canonical binary encoding of blocks, transactions and slips
+//
+// Everything that is hashed, signed, written to disk or sent to a peer goes
+// through this module so the bytes never depend on serde or on the layout
+// of our structs. All integers are big-endian, variable length fields are
+// prefixed with their length as a u32, and lists with their count as a u32.
+//
+// slip (57 bytes + block hash):
+//   address          33   compressed secp256k1 public key
+//   amount            8   nolan
+//   block_id          4
+//   transaction_id    4
+//   id                4
+//   block_hash        4 + n
+//
+// transaction:
+//   version           1   WIRE_FORMAT_VERSION, only when encoded on its own
+//   id                4
+//   tx_type           1   see TransactionType
+//   timestamp        16   milliseconds since the unix epoch
+//   sig              64   compact signature
+//   to                4 + slips
+//   from              4 + slips
+//   msg               4 + n
+//   path              4 + hops
+//
+// hop (130 bytes):
+//   from             33
+//   to               33
+//   sig              64   compact signature by from
+//
+// block header:
+//   version           1   WIRE_FORMAT_VERSION
+//   id                4
+//   previous_hash     4 + n
+//   merkle_root       4 + n
+//   timestamp        16
+//   creator          33
+//   difficulty        4   IEEE 754 bits
+//   paysplit          4   IEEE 754 bits
+//   treasury          8   nolan
+//   coinbase          8   nolan
+//   reclaimed         8   nolan
+//   burnfee           8   nolan
+//   paid_burnfee      8   nolan
+//
+// block:
+//   header
+//   transactions      4 + transactions without their version byte
+//
+// golden ticket, carried in the msg of a golden ticket transaction:
+//   target            4 + n   hash of the block it solves
+//   vote              1
+//   random            4 + n
+//   publickey        33
+//
+// Anything that changes these layouts must bump WIRE_FORMAT_VERSION.
+
+use std::io;
+
+use secp256k1::{PublicKey, Signature};
+
+use crate::{Amount, Block, BlockHeader, GoldenTicket, Hop, Slip, Transaction, TransactionType};
+
+pub const WIRE_FORMAT_VERSION: u8 = 4;
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+}
+
+pub(crate) fn write_bytes(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend(&(field.len() as u32).to_be_bytes());
+    bytes.extend(field);
+}
+
+pub(crate) struct WireReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> WireReader<'a> {
+        return WireReader { bytes };
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid_data("unexpected end of data"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        return Ok(taken);
+    }
+
+    pub(crate) fn read_u8(&mut self) -> io::Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub(crate) fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buffer = [0; 4];
+        buffer.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_be_bytes(buffer));
+    }
+
+    pub(crate) fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_be_bytes(buffer));
+    }
+
+    pub(crate) fn read_u128(&mut self) -> io::Result<u128> {
+        let mut buffer = [0; 16];
+        buffer.copy_from_slice(self.take(16)?);
+        return Ok(u128::from_be_bytes(buffer));
+    }
+
+    pub(crate) fn read_f32(&mut self) -> io::Result<f32> {
+        return Ok(f32::from_bits(self.read_u32()?));
+    }
+
+    pub(crate) fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        return Ok(self.take(len)?.to_vec());
+    }
+
+    pub(crate) fn read_publickey(&mut self) -> io::Result<PublicKey> {
+        return PublicKey::from_slice(self.take(33)?)
+            .map_err(|_| invalid_data("invalid public key"));
+    }
+
+    pub(crate) fn read_signature(&mut self) -> io::Result<Signature> {
+        return Signature::from_compact(self.take(64)?)
+            .map_err(|_| invalid_data("invalid signature"));
+    }
+
+    pub(crate) fn read_version(&mut self) -> io::Result<()> {
+        if self.read_u8()? != WIRE_FORMAT_VERSION {
+            return Err(invalid_data("unsupported wire format version"));
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn finish(&self) -> io::Result<()> {
+        if !self.bytes.is_empty() {
+            return Err(invalid_data("trailing bytes"));
+        }
+        return Ok(());
+    }
+}
+
+impl TransactionType {
+    pub(crate) fn to_byte(&self) -> u8 {
+        return match self {
+            TransactionType::Base => 0,
+            TransactionType::GoldenTicket => 1,
+            TransactionType::Fee => 2,
+            TransactionType::Rebroadcast => 3,
+            TransactionType::VIP => 4,
+            TransactionType::GoldenChunk => 5,
+        };
+    }
+
+    fn from_byte(byte: u8) -> io::Result<TransactionType> {
+        return match byte {
+            0 => Ok(TransactionType::Base),
+            1 => Ok(TransactionType::GoldenTicket),
+            2 => Ok(TransactionType::Fee),
+            3 => Ok(TransactionType::Rebroadcast),
+            4 => Ok(TransactionType::VIP),
+            5 => Ok(TransactionType::GoldenChunk),
+            _ => Err(invalid_data("unknown transaction type")),
+        };
+    }
+}
+
+impl Slip {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(57 + self.block_hash.len());
+        self.write_to(&mut bytes);
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Slip> {
+        let mut reader = WireReader::new(bytes);
+        let slip = Slip::read_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(slip);
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.address.serialize().iter());
+        bytes.extend(&self.amount.return_nolan().to_be_bytes());
+        bytes.extend(&self.block_id.to_be_bytes());
+        bytes.extend(&self.transaction_id.to_be_bytes());
+        bytes.extend(&self.id.to_be_bytes());
+        write_bytes(bytes, &self.block_hash);
+    }
+
+    fn read_from(reader: &mut WireReader) -> io::Result<Slip> {
+        return Ok(Slip {
+            address: reader.read_publickey()?,
+            amount: Amount::from_nolan(reader.read_u64()?),
+            block_id: reader.read_u32()?,
+            transaction_id: reader.read_u32()?,
+            id: reader.read_u32()?,
+            block_hash: reader.read_bytes()?,
+        });
+    }
+}
+
+impl Transaction {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![WIRE_FORMAT_VERSION];
+        self.write_to(&mut bytes);
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Transaction> {
+        let mut reader = WireReader::new(bytes);
+        reader.read_version()?;
+        let tx = Transaction::read_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(tx);
+    }
+
+    // bytes the transaction takes up inside a block
+    pub fn return_size(&self) -> usize {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.write_to(&mut bytes);
+        return bytes.len();
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(&self.id.to_be_bytes());
+        bytes.push(self.tx_type.to_byte());
+        bytes.extend(&self.timestamp.to_be_bytes());
+        bytes.extend(self.sig.serialize_compact().iter());
+
+        bytes.extend(&(self.to.len() as u32).to_be_bytes());
+        for slip in self.to.iter() {
+            slip.write_to(bytes);
+        }
+
+        bytes.extend(&(self.from.len() as u32).to_be_bytes());
+        for slip in self.from.iter() {
+            slip.write_to(bytes);
+        }
+
+        write_bytes(bytes, &self.msg);
+
+        bytes.extend(&(self.path.len() as u32).to_be_bytes());
+        for hop in self.path.iter() {
+            hop.write_to(bytes);
+        }
+    }
+
+    fn read_from(reader: &mut WireReader) -> io::Result<Transaction> {
+        let id = reader.read_u32()?;
+        let tx_type = TransactionType::from_byte(reader.read_u8()?)?;
+        let timestamp = reader.read_u128()?;
+        let sig = reader.read_signature()?;
+
+        let mut to: Vec<Slip> = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            to.push(Slip::read_from(reader)?);
+        }
+
+        let mut from: Vec<Slip> = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            from.push(Slip::read_from(reader)?);
+        }
+
+        let msg = reader.read_bytes()?;
+
+        let mut path: Vec<Hop> = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            path.push(Hop::read_from(reader)?);
+        }
+
+        return Ok(Transaction { id, tx_type, timestamp, sig, to, from, msg, path });
+    }
+}
+
+impl Hop {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.write_to(&mut bytes);
+        return bytes;
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.from.serialize().iter());
+        bytes.extend(self.to.serialize().iter());
+        bytes.extend(self.sig.serialize_compact().iter());
+    }
+
+    fn read_from(reader: &mut WireReader) -> io::Result<Hop> {
+        let from = reader.read_publickey()?;
+        let to = reader.read_publickey()?;
+        let sig = reader.read_signature()?;
+        return Ok(Hop { from, to, sig });
+    }
+}
+
+impl BlockHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![WIRE_FORMAT_VERSION];
+        bytes.extend(&self.id.to_be_bytes());
+        write_bytes(&mut bytes, &self.previous_hash);
+        write_bytes(&mut bytes, &self.merkle_root);
+        bytes.extend(&self.timestamp.to_be_bytes());
+        bytes.extend(self.creator.serialize().iter());
+        bytes.extend(&self.difficulty.to_bits().to_be_bytes());
+        bytes.extend(&self.paysplit.to_bits().to_be_bytes());
+        bytes.extend(&self.treasury.return_nolan().to_be_bytes());
+        bytes.extend(&self.coinbase.return_nolan().to_be_bytes());
+        bytes.extend(&self.reclaimed.return_nolan().to_be_bytes());
+        bytes.extend(&self.burnfee.return_nolan().to_be_bytes());
+        bytes.extend(&self.paid_burnfee.return_nolan().to_be_bytes());
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<BlockHeader> {
+        let mut reader = WireReader::new(bytes);
+        let header = BlockHeader::read_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(header);
+    }
+
+    fn read_from(reader: &mut WireReader) -> io::Result<BlockHeader> {
+        reader.read_version()?;
+        return Ok(BlockHeader {
+            id: reader.read_u32()?,
+            previous_hash: reader.read_bytes()?,
+            merkle_root: reader.read_bytes()?,
+            timestamp: reader.read_u128()?,
+            creator: reader.read_publickey()?,
+            difficulty: reader.read_f32()?,
+            paysplit: reader.read_f32()?,
+            treasury: Amount::from_nolan(reader.read_u64()?),
+            coinbase: Amount::from_nolan(reader.read_u64()?),
+            reclaimed: Amount::from_nolan(reader.read_u64()?),
+            burnfee: Amount::from_nolan(reader.read_u64()?),
+            paid_burnfee: Amount::from_nolan(reader.read_u64()?),
+        });
+    }
+}
+
+impl GoldenTicket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_bytes(&mut bytes, &self.target);
+        bytes.push(self.vote);
+        write_bytes(&mut bytes, &self.random);
+        bytes.extend(self.publickey.serialize().iter());
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<GoldenTicket> {
+        let mut reader = WireReader::new(bytes);
+        let golden_ticket = GoldenTicket {
+            target: reader.read_bytes()?,
+            vote: reader.read_u8()?,
+            random: reader.read_bytes()?,
+            publickey: reader.read_publickey()?,
+        };
+        reader.finish()?;
+        return Ok(golden_ticket);
+    }
+}
+
+impl Block {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_to_bytes();
+        bytes.extend(self.transactions_to_bytes());
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Block> {
+        let mut reader = WireReader::new(bytes);
+        let mut block = Block::read_header_from(&mut reader)?;
+        block.transactions = Block::read_transactions_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(block);
+    }
+
+    // the transactions always come last so a block can be split into
+    // header and body on disk
+    pub fn header_to_bytes(&self) -> Vec<u8> {
+        return self.return_header().to_bytes();
+    }
+
+    pub fn transactions_to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(&(self.transactions.len() as u32).to_be_bytes());
+        for tx in self.transactions.iter() {
+            tx.write_to(&mut bytes);
+        }
+        return bytes;
+    }
+
+    // reads a header written by header_to_bytes, the block returned has no
+    // transactions
+    pub fn header_from_bytes(bytes: &[u8]) -> io::Result<Block> {
+        let (block, header_len) = Block::header_from_prefix(bytes)?;
+        if header_len != bytes.len() {
+            return Err(invalid_data("trailing bytes"));
+        }
+        return Ok(block);
+    }
+
+    // reads the header at the start of an encoded block and returns it
+    // along with the number of bytes it took up
+    pub fn header_from_prefix(bytes: &[u8]) -> io::Result<(Block, usize)> {
+        let mut reader = WireReader::new(bytes);
+        let block = Block::read_header_from(&mut reader)?;
+        return Ok((block, bytes.len() - reader.bytes.len()));
+    }
+
+    pub fn transactions_from_bytes(bytes: &[u8]) -> io::Result<Vec<Transaction>> {
+        let mut reader = WireReader::new(bytes);
+        let transactions = Block::read_transactions_from(&mut reader)?;
+        reader.finish()?;
+        return Ok(transactions);
+    }
+
+    fn read_header_from(reader: &mut WireReader) -> io::Result<Block> {
+        return Ok(Block::from_header(BlockHeader::read_from(reader)?));
+    }
+
+    fn read_transactions_from(reader: &mut WireReader) -> io::Result<Vec<Transaction>> {
+        let mut transactions: Vec<Transaction> = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            transactions.push(Transaction::read_from(reader)?);
+        }
+        return Ok(transactions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Secp256k1, SecretKey};
+
+    fn decode_hex(hex: &str) -> Vec<u8> {
+        return (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+    }
+
+    fn encode_hex(bytes: &[u8]) -> String {
+        return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    }
+
+    fn create_test_slip() -> Slip {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut slip = Slip::new(PublicKey::from_secret_key(&secp, &secret_key));
+        slip.amount = Amount::from_nolan(150000000);
+        slip.block_id = 7;
+        slip.transaction_id = 3;
+        slip.id = 12;
+        slip.block_hash = vec![0xab; 4];
+        return slip;
+    }
+
+    fn create_test_transaction() -> Transaction {
+        let mut tx = Transaction::new(TransactionType::GoldenTicket);
+        tx.id = 5;
+        tx.timestamp = 1564790400000;
+        tx.sig = Signature::from_compact(&[[0x11; 32], [0x22; 32]].concat()).unwrap();
+        tx.to.push(create_test_slip());
+        tx.msg = vec![0xca, 0xfe];
+        tx.path.push(Hop {
+            from: create_test_slip().address,
+            to: create_test_slip().address,
+            sig: Signature::from_compact(&[[0x33; 32], [0x44; 32]].concat()).unwrap(),
+        });
+        return tx;
+    }
+
+    fn create_test_block() -> Block {
+        let mut block = Block::new(vec![0xcd; 4], create_test_slip().address);
+        block.id = 2;
+        block.timestamp = 1564790401000;
+        block.merkle_root = vec![0xef; 2];
+        block.difficulty = 2.0;
+        block.coinbase = Amount::from_nolan(1);
+        block.reclaimed = Amount::from_nolan(2);
+        block.burnfee = Amount::from_nolan(3);
+        block.paid_burnfee = Amount::from_nolan(4);
+        block.transactions.push(create_test_transaction());
+        return block;
+    }
+
+    static SLIP_BYTES: &str = concat!(
+        "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+        "0000000008f0d180",
+        "00000007",
+        "00000003",
+        "0000000c",
+        "00000004abababab",
+    );
+
+    static TRANSACTION_BYTES: &str = concat!(
+        "00000005",
+        "01",
+        "00000000000000000000016c54c6a400",
+        "1111111111111111111111111111111111111111111111111111111111111111",
+        "2222222222222222222222222222222222222222222222222222222222222222",
+        "00000001",
+    );
+
+    static PATH_BYTES: &str = concat!(
+        "00000001",
+        "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+        "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+        "3333333333333333333333333333333333333333333333333333333333333333",
+        "4444444444444444444444444444444444444444444444444444444444444444",
+    );
+
+    #[test]
+    fn slip_wire_format_matches_golden_vector() {
+        let slip = create_test_slip();
+        assert_eq!(encode_hex(&slip.to_bytes()), SLIP_BYTES);
+        assert_eq!(Slip::from_bytes(&decode_hex(SLIP_BYTES)).unwrap(), slip);
+    }
+
+    #[test]
+    fn transaction_wire_format_matches_golden_vector() {
+        let tx = create_test_transaction();
+        let expected = format!("04{}{}0000000000000002cafe{}", TRANSACTION_BYTES, SLIP_BYTES, PATH_BYTES);
+        assert_eq!(encode_hex(&tx.to_bytes()), expected);
+        assert_eq!(Transaction::from_bytes(&decode_hex(&expected)).unwrap(), tx);
+    }
+
+    #[test]
+    fn block_wire_format_matches_golden_vector() {
+        let block = create_test_block();
+        let expected_header = concat!(
+            "04",
+            "00000002",
+            "00000004cdcdcdcd",
+            "00000002efef",
+            "00000000000000000000016c54c6a7e8",
+            "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+            "40000000",
+            "3f000000",
+            "03faf42ce807a000",
+            "0000000000000001",
+            "0000000000000002",
+            "0000000000000003",
+            "0000000000000004",
+        );
+        let expected = format!(
+            "{}00000001{}{}0000000000000002cafe{}",
+            expected_header, TRANSACTION_BYTES, SLIP_BYTES, PATH_BYTES
+        );
+
+        assert_eq!(encode_hex(&block.header_to_bytes()), expected_header);
+        assert_eq!(encode_hex(&block.to_bytes()), expected);
+        assert_eq!(Block::from_bytes(&decode_hex(&expected)).unwrap(), block);
+        assert_eq!(BlockHeader::from_bytes(&decode_hex(expected_header)).unwrap(), block.return_header());
+        assert_eq!(Block::header_from_bytes(&decode_hex(expected_header)).unwrap().return_header(), block.return_header());
+    }
+
+    #[test]
+    fn golden_ticket_wire_format_matches_golden_vector() {
+        let golden_ticket = GoldenTicket {
+            target: vec![0xcd; 4],
+            vote: 1,
+            random: vec![0, 0, 0, 9],
+            publickey: create_test_slip().address,
+        };
+        let expected = concat!(
+            "00000004cdcdcdcd",
+            "01",
+            "0000000400000009",
+            "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+        );
+        assert_eq!(encode_hex(&golden_ticket.to_bytes()), expected);
+        assert_eq!(GoldenTicket::from_bytes(&decode_hex(expected)).unwrap(), golden_ticket);
+    }
+
+    #[test]
+    fn wire_format_rejects_bad_input() {
+        let mut bytes = create_test_transaction().to_bytes();
+        bytes[0] = WIRE_FORMAT_VERSION + 1;
+        assert!(Transaction::from_bytes(&bytes).is_err());
+
+        let mut bytes = create_test_block().to_bytes();
+        bytes.push(0);
+        assert!(Block::from_bytes(&bytes).is_err());
+
+        let bytes = create_test_slip().to_bytes();
+        assert!(Slip::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}